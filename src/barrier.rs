@@ -0,0 +1,155 @@
+//! A reusable barrier shared between processes
+//!
+//! The barrier is a semaphore set counting the processes which still have to
+//! arrive at and depart from the current generation. Arriving and departing
+//! both take one from their count, and whoever takes the last one is found out
+//! with a single `semop` which only succeeds if the count drops to zero:
+//!
+//! * The last process to arrive is the leader, and lets everyone depart.
+//! * The last process to depart lets the next generation arrive.
+//!
+//! So a process racing ahead into the next generation waits for the stragglers
+//! of the previous one to leave first.
+
+use std::cmp;
+use std::io::{Error, ErrorKind, Result};
+
+use {Backend, Op, OpenOptions, Semantics, SemaphoreSet};
+
+/// The number of processes which still have to arrive.
+const ARRIVE: usize = 0;
+/// The number of processes which still have to depart.
+const DEPART: usize = 1;
+/// The number of processes the barrier is for.
+const SIZE: usize = 2;
+
+/// A barrier enabling multiple processes to synchronize the beginning of some
+/// computation.
+///
+/// This is the cross-process equivalent of `std::sync::Barrier`: `wait`
+/// blocks until `n` processes have called it, and then lets all of them
+/// continue at once. The barrier is immediately usable again afterwards.
+///
+/// Arriving at the barrier is not undone when a process exits, so a process
+/// exiting while others are waiting for it leaves them blocked. Like the
+/// other primitives in this crate a barrier persists until it is removed, and
+/// is only available on unix.
+///
+/// # Example
+///
+/// ```
+/// use ipc::Barrier;
+///
+/// // a barrier for a single process never blocks
+/// let barrier = Barrier::new("barrier-example", 1).unwrap();
+/// assert!(barrier.wait().unwrap().is_leader());
+/// barrier.remove().unwrap();
+/// ```
+pub struct Barrier {
+    set: SemaphoreSet,
+    n: usize,
+}
+
+/// Returned by `Barrier::wait` when all processes have met at the barrier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl Barrier {
+    /// Opens the barrier named `name` for `n` processes, creating it if it
+    /// doesn't already exist.
+    ///
+    /// Like `std::sync::Barrier` a barrier for 0 processes behaves like one
+    /// for 1 process.
+    pub fn new(name: &str, n: usize) -> Result<Barrier> {
+        Barrier::with_options(&OpenOptions::new(), name, n)
+    }
+
+    /// Opens the barrier named `name` for `n` processes with the options
+    /// specified by `opts`.
+    ///
+    /// The `initial` count and semantics of `opts` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `OpenOptions::open_set`, an error of
+    /// kind `InvalidInput` is returned if the existing barrier is for a
+    /// different number of processes, and one of kind
+    /// `ipc::ErrorKind::Overflow` if `n` is larger than `i16::MAX`.
+    pub fn with_options(opts: &OpenOptions, name: &str,
+                        n: usize) -> Result<Barrier> {
+        let n = cmp::max(n, 1);
+        let mut opts = opts.clone();
+        opts.semantics(Semantics::Signal);
+        let set = opts.open_set(name, &[n, 0, n])?;
+        let size = set.value(SIZE)?;
+        if size != n {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("barrier is for {} processes, not {}",
+                                          size, n)))
+        }
+        Ok(Barrier { set, n })
+    }
+
+    /// Removes the barrier named `name` from the system.
+    ///
+    /// This is the same as `Semaphore::unlink`.
+    pub fn unlink(name: &str) -> Result<()> {
+        Barrier::unlink_with_options(&OpenOptions::new(), name)
+    }
+
+    /// Removes the barrier named `name` which was opened with the options
+    /// specified by `opts`.
+    ///
+    /// Only the naming options of `opts` are used.
+    pub fn unlink_with_options(opts: &OpenOptions, name: &str) -> Result<()> {
+        let mut opts = opts.clone();
+        opts.backend(Backend::SystemV);
+        opts.unlink(name)
+    }
+
+    /// Removes this barrier from the system, waking up any waiting processes
+    /// with an error.
+    pub fn remove(self) -> Result<()> {
+        self.set.remove()
+    }
+
+    /// Blocks the current process until all processes have called `wait`.
+    ///
+    /// Exactly one process of every generation is told that it is the
+    /// leader, through `BarrierWaitResult::is_leader`.
+    ///
+    /// # Errors
+    ///
+    /// The errors are those described in `Semaphore::acquire`.
+    pub fn wait(&self) -> Result<BarrierWaitResult> {
+        let leader = self.take(ARRIVE, Op::release(DEPART, self.n))?;
+        self.take(DEPART, Op::release(ARRIVE, self.n))?;
+        Ok(BarrierWaitResult(leader))
+    }
+
+    /// Takes one from the member `index`, applying `last` as well if it drops
+    /// to zero. Returns whether it did.
+    fn take(&self, index: usize, last: Op) -> Result<bool> {
+        loop {
+            // Either the count is exactly one and we're last...
+            if self.set.try_apply(&[Op::acquire(index, 1), Op::wait_zero(index),
+                                    last])? {
+                return Ok(true)
+            }
+            // ...or it's larger, and someone else is going to be...
+            if self.set.try_apply(&[Op::acquire(index, 2),
+                                    Op::release(index, 1)])? {
+                return Ok(false)
+            }
+            // ...or it's zero, and we wait for it to be raised before trying
+            // again.
+            self.set.apply(&[Op::acquire(index, 1), Op::release(index, 1)])?;
+        }
+    }
+}
+
+impl BarrierWaitResult {
+    /// Returns whether this process is the leader of its generation, which
+    /// is the last one to arrive at the barrier.
+    pub fn is_leader(&self) -> bool { self.0 }
+}
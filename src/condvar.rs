@@ -0,0 +1,198 @@
+//! A condition variable shared between processes
+//!
+//! The condition variable is a sequence number in a named shared memory
+//! region. Waiters read it while still holding the mutex, and then sleep in
+//! `futex` for as long as it holds that value. Notifying bumps the sequence
+//! number before waking anybody up, so a notification sent between a waiter
+//! unlocking the mutex and going to sleep makes the `futex` call return
+//! straight away rather than being lost.
+
+use std::io::Result;
+use std::mem;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+use libc;
+
+use futex;
+use mutex::{MutexGuard, Pod};
+use shm::{self, Shm};
+use {LockError, LockResult, OpenOptions};
+
+/// A condition variable which can be shared across processes.
+///
+/// Condition variables block a process until some condition on the value of
+/// a `Mutex` holds, without the process having to poll the mutex. They are
+/// used together with a mutex in the same way as `std::sync::Condvar`, and
+/// wakeups may be spurious, so the condition must be checked in a loop.
+///
+/// Like the other primitives in this crate a condition variable is named, and
+/// persists until it is removed with `remove` or `unlink`. It doesn't need to
+/// share the name of the mutex it is used with. This is only available on
+/// linux on x86, x86_64, arm and aarch64, as it is built on `futex`.
+///
+/// # Example
+///
+/// ```
+/// use ipc::{Condvar, Mutex};
+///
+/// let ready = Mutex::new("condvar-example", 0u8).unwrap();
+/// let cond = Condvar::new("condvar-example").unwrap();
+///
+/// // in another process
+/// *ready.lock().unwrap() = 1;
+/// cond.notify_all().unwrap();
+///
+/// let mut guard = ready.lock().unwrap();
+/// while *guard == 0 {
+///     guard = cond.wait(guard).unwrap();
+/// }
+/// ```
+pub struct Condvar {
+    shm: Shm,
+}
+
+/// Whether `Condvar::wait_timeout` returned because the timeout elapsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+/// The layout of the shared memory region backing a condition variable,
+/// which is ready to use when zeroed.
+#[repr(C)]
+struct Shared {
+    /// Bumped by every notification.
+    seq: AtomicU32,
+    /// The number of processes sleeping in `wait`, so notifying can skip the
+    /// system call when there are none.
+    waiters: AtomicU32,
+}
+
+impl Condvar {
+    /// Opens the condition variable named `name`, creating it if it doesn't
+    /// already exist.
+    pub fn new(name: &str) -> Result<Condvar> {
+        Condvar::with_options(&OpenOptions::new(), name)
+    }
+
+    /// Opens the condition variable named `name` with the options specified
+    /// by `opts`.
+    ///
+    /// Only `create`, `create_new`, `mode` and the naming options are used.
+    pub fn with_options(opts: &OpenOptions, name: &str) -> Result<Condvar> {
+        let (shm, _) = unsafe {
+            let name = shm::name(opts, &opts.name(name), "condvar",
+                                 opts.create)?;
+            Shm::open(&name, mem::size_of::<Shared>(), opts.create,
+                      opts.create_new, opts.mode)?
+        };
+        Ok(Condvar { shm })
+    }
+
+    /// Removes the condition variable named `name` from the system.
+    pub fn unlink(name: &str) -> Result<()> {
+        Condvar::unlink_with_options(&OpenOptions::new(), name)
+    }
+
+    /// Removes the condition variable named `name` which was opened with the
+    /// options specified by `opts`.
+    ///
+    /// Only the naming options of `opts` are used.
+    pub fn unlink_with_options(opts: &OpenOptions, name: &str) -> Result<()> {
+        let name = shm::name(opts, &opts.name(name), "condvar", false)?;
+        unsafe { Shm::unlink(&name) }
+    }
+
+    /// Removes this condition variable from the system.
+    ///
+    /// Processes which have it open can keep using it, but it is no longer
+    /// shared with processes opening it afterwards.
+    pub fn remove(self) -> Result<()> {
+        unsafe { self.shm.remove() }
+    }
+
+    /// Unlocks the mutex locked by `guard` and blocks until this condition
+    /// variable is notified, then locks the mutex again.
+    ///
+    /// The wakeup may be spurious, in which case the mutex is locked again
+    /// all the same.
+    ///
+    /// # Errors
+    ///
+    /// The errors are those described in `Mutex::lock`, for locking the mutex
+    /// again.
+    pub fn wait<'a, T: Pod>(&self, guard: MutexGuard<'a, T>)
+                            -> LockResult<MutexGuard<'a, T>> {
+        self.wait_until(guard, None).map(|(g, _)| g).map_err(|e| match e {
+            LockError::Io(e) => LockError::Io(e),
+            LockError::OwnerDied((g, _)) => LockError::OwnerDied(g),
+        })
+    }
+
+    /// Like `wait`, but gives up waiting for a notification once `dur` has
+    /// elapsed.
+    ///
+    /// The mutex is locked again either way, without a timeout.
+    pub fn wait_timeout<'a, T: Pod>(&self, guard: MutexGuard<'a, T>,
+                                    dur: Duration)
+                                    -> LockResult<(MutexGuard<'a, T>,
+                                                   WaitTimeoutResult)> {
+        self.wait_until(guard, Instant::now().checked_add(dur))
+    }
+
+    fn wait_until<'a, T: Pod>(&self, guard: MutexGuard<'a, T>,
+                              deadline: Option<Instant>)
+                              -> LockResult<(MutexGuard<'a, T>,
+                                             WaitTimeoutResult)> {
+        let shared = self.shared();
+        let mutex = MutexGuard::mutex(&guard);
+
+        // Read the sequence number and announce ourselves while the mutex is
+        // still held, so that whoever changes the condition next is
+        // guaranteed to notify us.
+        let seq = shared.seq.load(SeqCst);
+        shared.waiters.fetch_add(1, SeqCst);
+        drop(guard);
+        let timeout = deadline.map(|d| {
+            d.saturating_duration_since(Instant::now())
+        });
+        let res = unsafe { futex::wait(&shared.seq, seq, timeout) };
+        shared.waiters.fetch_sub(1, SeqCst);
+        res?;
+
+        let expired = deadline.is_some_and(|d| Instant::now() >= d);
+        let res = WaitTimeoutResult(expired && shared.seq.load(SeqCst) == seq);
+        match mutex.lock() {
+            Ok(g) => Ok((g, res)),
+            Err(LockError::Io(e)) => Err(LockError::Io(e)),
+            Err(LockError::OwnerDied(g)) => Err(LockError::OwnerDied((g, res))),
+        }
+    }
+
+    /// Wakes up one process blocked on this condition variable, if any.
+    pub fn notify_one(&self) -> Result<()> {
+        self.notify(1)
+    }
+
+    /// Wakes up every process blocked on this condition variable.
+    pub fn notify_all(&self) -> Result<()> {
+        self.notify(libc::c_int::MAX)
+    }
+
+    fn notify(&self, n: libc::c_int) -> Result<()> {
+        let shared = self.shared();
+        shared.seq.fetch_add(1, SeqCst);
+        if shared.waiters.load(SeqCst) > 0 {
+            unsafe { futex::wake(&shared.seq, n)?; }
+        }
+        Ok(())
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { &*(self.shm.as_ptr() as *const Shared) }
+    }
+}
+
+impl WaitTimeoutResult {
+    /// Returns whether the wait timed out without being notified.
+    pub fn timed_out(&self) -> bool { self.0 }
+}
@@ -0,0 +1,146 @@
+//! Typed errors for semaphore operations
+//!
+//! All fallible functions in this crate return a `std::io::Error`, but a few
+//! failure modes are interesting enough that a long-running process may want
+//! to recover from them rather than give up. Those errors carry an `Error` as
+//! their inner error which can be retrieved with `Error::from_io`.
+//!
+//! Locking a lock which holds a value returns a `LockError` instead, so that
+//! the guard can be handed out along with the news that the previous owner
+//! died.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// The kinds of semaphore failures which can be told apart through `Error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The semaphore was removed from the system while it was still in use,
+    /// for example by another process calling `Semaphore::remove`.
+    Removed,
+    /// The current process does not have permission to operate on the
+    /// semaphore.
+    PermissionDenied,
+    /// The operation would have taken the count of the semaphore past the
+    /// largest value supported by the system.
+    Overflow,
+    /// The name of the semaphore maps to the same System V key as another
+    /// semaphore with a different name, so it can't be used.
+    ///
+    /// This is rare, but possible as keys are derived from only a few bits of
+    /// the inode of the key file. Picking another name avoids it.
+    Collision,
+}
+
+/// A semaphore error which can be recovered from, see the module
+/// documentation.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    code: Option<i32>,
+}
+
+impl Error {
+    /// Creates an `io::Error` wrapping a new `Error` of kind `kind`.
+    ///
+    /// The `code` is the raw OS error the failure originated from, if any.
+    pub(crate) fn io(kind: ErrorKind, code: Option<i32>) -> io::Error {
+        let io_kind = match kind {
+            ErrorKind::Removed => io::ErrorKind::NotFound,
+            ErrorKind::PermissionDenied => io::ErrorKind::PermissionDenied,
+            ErrorKind::Overflow => io::ErrorKind::InvalidInput,
+            ErrorKind::Collision => io::ErrorKind::AlreadyExists,
+        };
+        io::Error::new(io_kind, Error { kind, code })
+    }
+
+    /// Returns the `Error` contained in `err`, if any.
+    pub fn from_io(err: &io::Error) -> Option<&Error> {
+        err.get_ref().and_then(|e| e.downcast_ref::<Error>())
+    }
+
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> ErrorKind { self.kind }
+
+    /// Returns the OS error this error originated from, if any.
+    pub fn raw_os_error(&self) -> Option<i32> { self.code }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self.kind {
+            ErrorKind::Removed => "semaphore was removed",
+            ErrorKind::PermissionDenied => "permission denied",
+            ErrorKind::Overflow => "semaphore count out of range",
+            ErrorKind::Collision => "semaphore key collides with another semaphore",
+        };
+        match self.code {
+            Some(code) => write!(f, "{} (os error {})", desc, code),
+            None => f.write_str(desc),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The result of locking one of the locks in this crate which hold a value.
+pub type LockResult<G> = result::Result<G, LockError<G>>;
+
+/// The result of attempting to lock one of the locks in this crate which hold
+/// a value, which is `None` if the lock could not be acquired in time.
+pub type TryLockResult<G> = result::Result<Option<G>, LockError<G>>;
+
+/// The error returned when locking one of the locks in this crate which hold
+/// a value in shared memory, such as `Mutex`.
+pub enum LockError<G> {
+    /// The lock could not be operated on.
+    Io(io::Error),
+    /// The lock was acquired, but its previous owner exited while holding it.
+    ///
+    /// The lock was released on behalf of the dead process, which may have
+    /// left the protected value half-written. The guard is provided so that
+    /// the new owner can check and repair the value; once it is dropped the
+    /// value is considered consistent again.
+    OwnerDied(G),
+}
+
+impl<G> LockError<G> {
+    /// Returns the guard of an `OwnerDied` error, so the new owner can take
+    /// over as if locking had succeeded.
+    ///
+    /// Any other error is returned as is.
+    pub fn into_guard(self) -> io::Result<G> {
+        match self {
+            LockError::Io(e) => Err(e),
+            LockError::OwnerDied(guard) => Ok(guard),
+        }
+    }
+}
+
+impl<G> From<io::Error> for LockError<G> {
+    fn from(err: io::Error) -> LockError<G> { LockError::Io(err) }
+}
+
+impl<G> fmt::Debug for LockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Io(ref e) => f.debug_tuple("Io").field(e).finish(),
+            LockError::OwnerDied(..) => f.write_str("OwnerDied(..)"),
+        }
+    }
+}
+
+impl<G> fmt::Display for LockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockError::Io(ref e) => e.fmt(f),
+            LockError::OwnerDied(..) => {
+                f.write_str("previous owner of the lock exited while holding it")
+            }
+        }
+    }
+}
+
+impl<G> error::Error for LockError<G> {}
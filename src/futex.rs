@@ -0,0 +1,256 @@
+//! Semaphores built from atomics in shared memory
+//!
+//! Every operation on a System V semaphore is a system call, even when the
+//! semaphore is uncontended. Here the count instead lives in a named shared
+//! memory region and is adjusted with atomic instructions, so the kernel is
+//! only involved through `futex` when a process actually needs to sleep or
+//! wake somebody up.
+//!
+//! The kernel knows nothing about the count, so operations are never undone
+//! when a process exits.
+
+#![allow(bad_style)]
+
+use std::io::{Result, Error, ErrorKind};
+use std::mem;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+use libc;
+
+use shm::{self, Shm};
+use {error, OpenOptions, SemaphoreStats};
+
+pub struct Semaphore { shm: Shm }
+
+/// The layout of the shared memory region backing a semaphore.
+#[repr(C)]
+struct Shared {
+    /// `UNINIT` until the creator has stored the initial count, then `READY`.
+    state: AtomicU32,
+    count: AtomicU32,
+    /// The number of processes sleeping in `futex` waiting for the count to
+    /// increase, so `post` can skip the system call when there are none.
+    waiters: AtomicU32,
+}
+
+const UNINIT: u32 = 0;
+const READY: u32 = 1;
+
+#[cfg(target_arch = "x86_64")]
+const SYS_futex: libc::c_long = 202;
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+const SYS_futex: libc::c_long = 240;
+#[cfg(target_arch = "aarch64")]
+const SYS_futex: libc::c_long = 98;
+
+const FUTEX_WAIT: libc::c_int = 0;
+const FUTEX_WAKE: libc::c_int = 1;
+
+extern "C" {
+    fn syscall(num: libc::c_long, ...) -> libc::c_long;
+}
+
+/// Sleeps until `word` is woken up by `wake`, as long as it still holds `val`.
+///
+/// A timeout, signal, or `word` not holding `val` in the first place are all
+/// reported as a normal wakeup, so callers must re-check their condition in a
+/// loop.
+pub unsafe fn wait(word: &AtomicU32, val: u32,
+                   timeout: Option<Duration>) -> Result<()> {
+    let timeout = timeout.map(|dur| libc::timespec {
+        tv_sec: ::std::cmp::min(dur.as_secs(), i32::MAX as u64) as libc::time_t,
+        tv_nsec: dur.subsec_nanos() as libc::c_long,
+    });
+    let timeout = match timeout {
+        Some(ref t) => t as *const libc::timespec,
+        None => ::std::ptr::null(),
+    };
+    if syscall(SYS_futex, word as *const AtomicU32, FUTEX_WAIT, val,
+               timeout) == 0 {
+        return Ok(())
+    }
+    match Error::last_os_error() {
+        ref e if e.raw_os_error() == Some(libc::EAGAIN) ||
+                 e.raw_os_error() == Some(libc::EINTR) ||
+                 e.raw_os_error() == Some(libc::ETIMEDOUT) => Ok(()),
+        e => Err(e)
+    }
+}
+
+/// Wakes up at most `n` processes sleeping in `wait` on `word`.
+pub unsafe fn wake(word: &AtomicU32, n: libc::c_int) -> Result<()> {
+    if syscall(SYS_futex, word as *const AtomicU32, FUTEX_WAKE, n) < 0 {
+        return Err(Error::last_os_error())
+    }
+    Ok(())
+}
+
+impl Semaphore {
+    pub unsafe fn new(name: &str, opts: &OpenOptions) -> Result<Semaphore> {
+        if opts.initial > libc::c_int::MAX as usize {
+            return Err(error::Error::io(error::ErrorKind::Overflow, None))
+        }
+        let (shm, created) = Shm::open(&shm::name(opts, name, "futex",
+                                                  opts.create)?,
+                                       mem::size_of::<Shared>(),
+                                       opts.create, opts.create_new,
+                                       opts.mode)?;
+        let sem = Semaphore { shm };
+        let shared = sem.shared();
+
+        // Unlike System V semaphores the region is zeroed on creation, so the
+        // creator can publish the initial count with a single store that
+        // anyone racing with it waits for.
+        if created {
+            shared.count.store(opts.initial as u32, SeqCst);
+            shared.state.store(READY, SeqCst);
+            wake(&shared.state, libc::c_int::MAX)?;
+            return Ok(sem)
+        }
+        let deadline = Instant::now() + opts.init_timeout;
+        while shared.state.load(SeqCst) != READY {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::new(ErrorKind::TimedOut,
+                                      "timed out waiting for sem to be initialized"))
+            }
+            wait(&shared.state, UNINIT, Some(deadline - now))?;
+        }
+        Ok(sem)
+    }
+
+    fn shared(&self) -> &Shared {
+        unsafe { &*(self.shm.as_ptr() as *const Shared) }
+    }
+
+    pub unsafe fn remove(self) -> Result<()> {
+        self.shm.remove()
+    }
+
+    pub unsafe fn unlink(name: &str, opts: &OpenOptions) -> Result<()> {
+        Shm::unlink(&shm::name(opts, name, "futex", false)?)
+    }
+
+    pub unsafe fn wait(&self, n: usize, deadline: Option<Instant>,
+                       _undo: bool) -> Result<bool> {
+        let n = amount(n)?;
+        let shared = self.shared();
+        loop {
+            let cur = shared.count.load(SeqCst);
+            if cur >= n {
+                if shared.count.compare_exchange(cur, cur - n, SeqCst,
+                                                 SeqCst).is_ok() {
+                    self.taken(cur, n)?;
+                    return Ok(true)
+                }
+                continue
+            }
+            if !self.sleep(cur, deadline)? { return Ok(false) }
+        }
+    }
+
+    pub unsafe fn try_wait(&self, n: usize, _undo: bool) -> Result<bool> {
+        let n = amount(n)?;
+        let shared = self.shared();
+        let mut cur = shared.count.load(SeqCst);
+        while cur >= n {
+            match shared.count.compare_exchange(cur, cur - n, SeqCst, SeqCst) {
+                Ok(..) => { self.taken(cur, n)?; return Ok(true) }
+                Err(actual) => cur = actual,
+            }
+        }
+        Ok(false)
+    }
+
+    pub unsafe fn wait_zero(&self, deadline: Option<Instant>) -> Result<bool> {
+        let shared = self.shared();
+        loop {
+            let cur = shared.count.load(SeqCst);
+            if cur == 0 { return Ok(true) }
+            if !self.sleep(cur, deadline)? { return Ok(false) }
+        }
+    }
+
+    pub unsafe fn try_wait_zero(&self) -> Result<bool> {
+        Ok(self.shared().count.load(SeqCst) == 0)
+    }
+
+    /// Sleep until the count may no longer be `cur`, returning `false` if
+    /// `deadline` has already passed instead.
+    unsafe fn sleep(&self, cur: u32, deadline: Option<Instant>) -> Result<bool> {
+        let shared = self.shared();
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline { return Ok(false) }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+        // Announce ourselves before sleeping. If someone slips in between
+        // our load and the futex call the count no longer holds `cur`, and
+        // the kernel returns straight away.
+        shared.waiters.fetch_add(1, SeqCst);
+        let res = wait(&shared.count, cur, timeout);
+        shared.waiters.fetch_sub(1, SeqCst);
+        res.map(|()| true)
+    }
+
+    /// Called after taking `n` from a count of `cur`, to wake up anyone
+    /// waiting for the count to reach zero.
+    unsafe fn taken(&self, cur: u32, n: u32) -> Result<()> {
+        let shared = self.shared();
+        if n > 0 && cur == n && shared.waiters.load(SeqCst) > 0 {
+            wake(&shared.count, libc::c_int::MAX)?;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn post(&self, n: usize, _undo: bool) -> Result<()> {
+        let n = amount(n)?;
+        if n == 0 { return Ok(()) }
+        let shared = self.shared();
+        let mut cur = shared.count.load(SeqCst);
+        loop {
+            if cur > libc::c_int::MAX as u32 - n {
+                return Err(error::Error::io(error::ErrorKind::Overflow, None))
+            }
+            match shared.count.compare_exchange(cur, cur + n, SeqCst, SeqCst) {
+                Ok(..) => break,
+                Err(actual) => cur = actual,
+            }
+        }
+        // Waiters may want differing amounts, so wake all of them up and let
+        // them sort it out amongst themselves.
+        if shared.waiters.load(SeqCst) > 0 {
+            wake(&shared.count, libc::c_int::MAX)?;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn set_permissions(&self, mode: u32) -> Result<()> {
+        self.shm.set_permissions(mode)
+    }
+
+    pub unsafe fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        self.shm.set_owner(uid, gid)
+    }
+
+    pub unsafe fn stats(&self) -> Result<SemaphoreStats> {
+        Err(Error::other("futex semaphores only support querying the value"))
+    }
+
+    pub unsafe fn value(&self) -> Result<usize> {
+        Ok(self.shared().count.load(SeqCst) as usize)
+    }
+}
+
+/// Convert a number of permits into an adjustment of the count, which is kept
+/// within the range of a `c_int` like the other backends.
+fn amount(n: usize) -> Result<u32> {
+    if n > libc::c_int::MAX as usize {
+        return Err(error::Error::io(error::ErrorKind::Overflow, None))
+    }
+    Ok(n as u32)
+}
@@ -0,0 +1,17 @@
+//! The hash used to derive system names from semaphore names
+//!
+//! The names given to the system have to be the same for every process using
+//! a semaphore, no matter which version of this crate or of Rust it was built
+//! with, and other languages need to be able to compute them too. This rules
+//! out `std::hash`, whose output isn't guaranteed to be stable, so this is a
+//! plain 64-bit FNV-1a.
+
+const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const PRIME: u64 = 0x100000001b3;
+
+/// Hash `bytes` with 64-bit FNV-1a.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(OFFSET_BASIS, |h, &b| {
+        (h ^ b as u64).wrapping_mul(PRIME)
+    })
+}
@@ -0,0 +1,117 @@
+//! A count down latch shared between processes
+//!
+//! A latch is a semaphore whose count is the number of tasks still
+//! outstanding. Each task takes one resource when it is done, and anyone
+//! waiting on the latch waits for the count to reach zero.
+
+use std::io::Result;
+use std::time::{Duration, Instant};
+
+use {OpenOptions, Semantics, Semaphore};
+
+/// A single-use barrier which opens once it has been counted down to zero.
+///
+/// This is useful for fan-in synchronization, for example a parent process
+/// spawning a number of workers and waiting until all of them have finished
+/// their setup. Unlike with a barrier the processes counting down never
+/// block.
+///
+/// A latch is backed by a semaphore waited on with `Semaphore::wait_zero`, so
+/// it shares its name with semaphores and is only available on unix. Counting
+/// down is never undone when a process exits, so a worker which crashes before
+/// counting down keeps the latch closed.
+///
+/// # Example
+///
+/// ```
+/// use ipc::Latch;
+///
+/// let latch = Latch::new("latch-example", 2).unwrap();
+///
+/// // in each of two workers
+/// Latch::new("latch-example", 2).unwrap().count_down().unwrap();
+/// Latch::new("latch-example", 2).unwrap().count_down().unwrap();
+///
+/// // in the coordinator
+/// latch.wait().unwrap();
+/// latch.remove().unwrap();
+/// ```
+pub struct Latch {
+    sem: Semaphore,
+}
+
+impl Latch {
+    /// Opens the latch named `name`, creating it with a count of `count` if
+    /// it doesn't already exist.
+    ///
+    /// Every process using the latch should open it with the same `count`,
+    /// as only the creator's is used.
+    pub fn new(name: &str, count: usize) -> Result<Latch> {
+        Latch::with_options(OpenOptions::new().initial(count), name)
+    }
+
+    /// Opens the latch named `name` with the options specified by `opts`.
+    ///
+    /// The initial count of the semaphore is the count of the latch, and the
+    /// semantics are always `Semantics::Signal`.
+    pub fn with_options(opts: &OpenOptions, name: &str) -> Result<Latch> {
+        let mut opts = opts.clone();
+        opts.semantics(Semantics::Signal);
+        Ok(Latch { sem: opts.open(name)? })
+    }
+
+    /// Decrements the count of this latch, opening it once it reaches zero.
+    ///
+    /// Counting down a latch which is already open does nothing.
+    pub fn count_down(&self) -> Result<()> {
+        self.sem.try_acquire().map(|_| ())
+    }
+
+    /// Returns the number of times this latch still has to be counted down
+    /// before it opens.
+    pub fn count(&self) -> Result<usize> {
+        self.sem.value()
+    }
+
+    /// Blocks until this latch is open.
+    pub fn wait(&self) -> Result<()> {
+        self.sem.wait_zero()
+    }
+
+    /// Returns whether this latch is open, without blocking.
+    pub fn try_wait(&self) -> Result<bool> {
+        self.sem.try_wait_zero()
+    }
+
+    /// Blocks until this latch is open for at most `dur`, returning whether
+    /// it opened.
+    pub fn wait_timeout(&self, dur: Duration) -> Result<bool> {
+        self.sem.wait_zero_timeout(dur)
+    }
+
+    /// Blocks until this latch is open or `deadline` passes, returning
+    /// whether it opened.
+    pub fn wait_until(&self, deadline: Instant) -> Result<bool> {
+        self.sem.wait_zero_until(deadline)
+    }
+
+    /// Removes the latch named `name` from the system.
+    ///
+    /// This is the same as `Semaphore::unlink`.
+    pub fn unlink(name: &str) -> Result<()> {
+        Latch::unlink_with_options(&OpenOptions::new(), name)
+    }
+
+    /// Removes the latch named `name` which was opened with the options
+    /// specified by `opts`.
+    ///
+    /// This is the same as `OpenOptions::unlink`.
+    pub fn unlink_with_options(opts: &OpenOptions, name: &str) -> Result<()> {
+        opts.unlink(name)
+    }
+
+    /// Removes this latch from the system, see `Semaphore::remove`.
+    pub fn remove(self) -> Result<()> {
+        self.sem.remove()
+    }
+}
@@ -19,21 +19,42 @@
 //! };
 //!
 //! // lock the semaphore
-//! let guard = s.access();
+//! let guard = s.access().unwrap();
 //!
 //! // unlock the semaphore
 //! drop(guard);
 //!
 //! // manage the semaphore count manually
-//! s.acquire();
-//! s.release();
+//! s.acquire().unwrap();
+//! s.release().unwrap();
 //! ```
 
 #![allow(non_camel_case_types)]
 
 extern crate libc;
 
+use std::env;
 use std::io::Result;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+pub use barrier::{Barrier, BarrierWaitResult};
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+pub use condvar::{Condvar, WaitTimeoutResult};
+pub use error::{Error, ErrorKind, LockError, LockResult, TryLockResult};
+#[cfg(unix)]
+pub use latch::Latch;
+#[cfg(unix)]
+pub use mutex::{Mutex, MutexGuard, Pod};
+#[cfg(unix)]
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(unix)]
+pub use set::{Op, SemaphoreSet};
 
 /// An atomic counter which can be shared across processes.
 ///
@@ -41,14 +62,142 @@ use std::io::Result;
 /// the count is 0, waiting for a process to invoke `release` through some
 /// mechanism.
 pub struct Semaphore {
-    inner: imp::Semaphore,
+    inner: Inner,
+    semantics: Semantics,
 }
 
 /// An RAII guard used to release a semaphore automatically when it falls out
 /// of scope.
+///
+/// Guards always use `Semantics::Lock`, regardless of the semantics of the
+/// semaphore they were created from. If a process exits while holding a guard
+/// the resources it holds are returned to the semaphore by the system.
 #[must_use]
 pub struct Guard<'a> {
     sem: &'a Semaphore,
+    cnt: usize,
+}
+
+/// A snapshot of the state of a `Semaphore`, as returned by
+/// `Semaphore::stats`.
+///
+/// Each field is read separately from the system, so the snapshot may be
+/// slightly inconsistent if other processes are using the semaphore at the
+/// same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SemaphoreStats {
+    value: usize,
+    waiters: usize,
+    zero_waiters: usize,
+    last_pid: u32,
+}
+
+/// The id of a System V semaphore set, as returned by `semget`.
+#[cfg(unix)]
+pub type RawSemId = i32;
+
+/// A trait to extract the raw System V semaphore id from an object.
+///
+/// This is analogous to `std::os::unix::io::AsRawFd`.
+#[cfg(unix)]
+pub trait AsRawSemId {
+    /// Returns the raw semaphore id, or -1 if the object isn't backed by a
+    /// System V semaphore.
+    fn as_raw_semid(&self) -> RawSemId;
+}
+
+/// A trait to open an object from a raw System V semaphore id.
+///
+/// Unlike `std::os::unix::io::FromRawFd` this is safe, as the id is
+/// validated with the system first.
+#[cfg(unix)]
+pub trait FromRawSemId: Sized {
+    /// Opens the object with the raw semaphore id `semid`.
+    fn from_raw_semid(semid: RawSemId) -> Result<Self>;
+}
+
+/// Options and flags which can be used to configure how a semaphore is
+/// opened.
+///
+/// This builder is modeled after `std::fs::OpenOptions` and is created through
+/// `Semaphore::options`. By default a semaphore is created with a count of 0
+/// if it doesn't already exist, and opened otherwise.
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    create: bool,
+    create_new: bool,
+    initial: usize,
+    mode: u32,
+    per_user: bool,
+    dir: Option<PathBuf>,
+    namespace: Option<String>,
+    semantics: Semantics,
+    backend: Backend,
+    init_timeout: Duration,
+}
+
+/// The kinds of system semaphores which can back a `Semaphore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// System V semaphores on unix, or the native semaphores on windows.
+    ///
+    /// This is the default, and the only backend supporting every feature of
+    /// `Semaphore`. Names are mangled as described in `Semaphore::new`.
+    SystemV,
+    /// POSIX named semaphores, as created by `sem_open`.
+    ///
+    /// The name is passed to the system exactly as given, so it should
+    /// generally start with a `/` and contain no other slashes. This allows
+    /// sharing the semaphore with programs written in other languages, for
+    /// example a name of `/foo` refers to `/dev/shm/sem.foo` on linux.
+    ///
+    /// POSIX semaphores never undo operations, so `Semantics::Lock` behaves
+    /// like `Semantics::Signal`. Acquiring several resources at once is not
+    /// atomic, their permissions can't be changed after creation, and only
+    /// `value` is available of the statistics. This backend is only
+    /// available on unix.
+    Posix,
+    /// A counter in shared memory which is adjusted with atomic instructions,
+    /// only calling into the kernel through `futex` when a process needs to
+    /// block or wake up a blocked process.
+    ///
+    /// This is much faster than `SystemV` when the semaphore is mostly
+    /// uncontended, as acquiring and releasing it involve no system calls.
+    /// Names are mangled as for `SystemV` and the counter lives in a shared
+    /// memory object under `/dev/shm`.
+    ///
+    /// Operations are never undone, so `Semantics::Lock` behaves like
+    /// `Semantics::Signal` and a process which dies while holding a guard
+    /// leaks its resources. Removing the semaphore does not wake up blocked
+    /// waiters, and only `value` is available of the statistics. This backend
+    /// is only available on linux on x86, x86_64, arm and aarch64.
+    Futex,
+}
+
+/// How the `acquire` and `release` operations of a semaphore interact with
+/// processes exiting.
+///
+/// This is a property of a `Semaphore` handle rather than of the system-wide
+/// semaphore, so different processes may use the same semaphore with
+/// different semantics. It is only meaningful on unix; on windows operations
+/// are never undone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Semantics {
+    /// Every operation is undone by the system when the process performing it
+    /// exits.
+    ///
+    /// This is appropriate when the semaphore is used as a lock, as a process
+    /// which dies after acquiring a resource will not leave it acquired
+    /// forever. It is inappropriate for hand-offs, as a process which releases
+    /// a resource for another process and then exits will have its release
+    /// silently reverted. This is the default.
+    Lock,
+    /// Operations are never undone.
+    ///
+    /// This is appropriate when the semaphore is used to signal events between
+    /// processes, for example a producer releasing a resource for a consumer
+    /// to acquire later.
+    Signal,
 }
 
 impl Semaphore {
@@ -62,7 +211,13 @@ impl Semaphore {
     ///
     /// Note that the name provided will be mangled as necessary when passed to
     /// the underlying system, so the name is not necessarily compatible with
-    /// other processes using semaphores.
+    /// other processes using semaphores. The mangling is stable across
+    /// versions of this crate, and `OpenOptions::key_for` documents it for
+    /// programs which don't use this crate.
+    ///
+    /// This is equivalent to `Semaphore::options().initial(cnt).open(name)`;
+    /// see `OpenOptions` for finer control over whether the semaphore is
+    /// created or opened.
     ///
     /// # Errors
     ///
@@ -79,29 +234,294 @@ impl Semaphore {
     /// let sem2 = Semaphore::new("foo", 1 /* ignored */).unwrap();
     /// ```
     pub fn new(name: &str, cnt: usize) -> Result<Semaphore> {
-        Ok(Semaphore {
-            inner: unsafe { try!(imp::Semaphore::new(name, cnt)) }
-        })
+        Semaphore::options().initial(cnt).open(name)
+    }
+
+    /// Returns a new `OpenOptions` to configure how a semaphore is created or
+    /// opened.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ipc::Semaphore;
+    ///
+    /// let sem = Semaphore::options().initial(4).open("options-example").unwrap();
+    /// let sem2 = Semaphore::options().create(false).open("options-example").unwrap();
+    /// ```
+    pub fn options() -> OpenOptions { OpenOptions::new() }
+
+    /// Removes this semaphore from the system.
+    ///
+    /// Named semaphores outlive the processes which use them, so one process
+    /// should call this when the semaphore is no longer needed. On unix the
+    /// kernel object is destroyed immediately, waking up any blocked waiters
+    /// with an error, and the key file used to name it is deleted. On windows
+    /// the semaphore is destroyed once every process has closed it.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the semaphore has already been removed.
+    pub fn remove(self) -> Result<()> {
+        unsafe { self.inner.remove() }
+    }
+
+    /// Removes the semaphore named `name` from the system.
+    ///
+    /// This is the same as `remove` except that the semaphore doesn't need to
+    /// be opened first. To remove a semaphore of another backend use
+    /// `OpenOptions::unlink`. A `SemaphoreSet` is named like a semaphore, and
+    /// can be removed with this too.
+    ///
+    /// # Errors
+    ///
+    /// An error of kind `NotFound` is returned if there is no semaphore named
+    /// `name`. On windows an error is always returned, as a semaphore can only
+    /// be removed by closing every handle to it.
+    pub fn unlink(name: &str) -> Result<()> {
+        Semaphore::options().unlink(name)
+    }
+
+    /// Creates a new semaphore without a name with the given count.
+    ///
+    /// An anonymous semaphore can't be opened by name, but it can be handed
+    /// to child processes with `pass_to`. This is equivalent to
+    /// `Semaphore::options().initial(cnt).mode(0o600).create_anonymous()`, so
+    /// only processes of the current user can use the semaphore.
+    ///
+    /// Like named semaphores an anonymous semaphore outlives the processes
+    /// using it, so one of them should `remove` it once it's no longer needed.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    /// use ipc::Semaphore;
+    ///
+    /// let sem = Semaphore::anonymous(0).unwrap();
+    /// let mut cmd = Command::new("child");
+    /// sem.pass_to(&mut cmd, "CHILD_SEMAPHORE").unwrap();
+    /// let mut child = cmd.spawn().unwrap();
+    ///
+    /// // in the child, `Semaphore::from_inherited("CHILD_SEMAPHORE")` opens
+    /// // the same semaphore to release it
+    /// sem.acquire().unwrap();
+    /// child.wait().unwrap();
+    /// sem.remove().unwrap();
+    /// ```
+    #[cfg(unix)]
+    pub fn anonymous(cnt: usize) -> Result<Semaphore> {
+        Semaphore::options().initial(cnt).mode(0o600).create_anonymous()
+    }
+
+    /// Opens a semaphore passed to this process by its parent with `pass_to`.
+    ///
+    /// This is equivalent to `Semaphore::options().open_inherited(var)`.
+    #[cfg(unix)]
+    pub fn from_inherited(var: &str) -> Result<Semaphore> {
+        Semaphore::options().open_inherited(var)
+    }
+
+    /// Arranges for the process spawned by `cmd` to be able to open this
+    /// semaphore with `Semaphore::from_inherited(var)`.
+    ///
+    /// The id of the semaphore is passed in the environment variable `var`,
+    /// so different semaphores passed to the same process need different
+    /// variables. This works for named semaphores too, as long as they use
+    /// `Backend::SystemV`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned for semaphores of other backends.
+    #[cfg(unix)]
+    pub fn pass_to(&self, cmd: &mut Command, var: &str) -> Result<()> {
+        match self.as_raw_semid() {
+            -1 => Err(std::io::Error::other("only System V semaphores can be \
+                                             passed to child processes")),
+            semid => {
+                cmd.env(var, semid.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Opens the System V semaphore set with the id `semid`.
+    ///
+    /// The id may come from `into_raw` or `as_raw_semid` in another process,
+    /// or from C code calling `semget` directly, in which case the first
+    /// member of the set is used as the semaphore. The returned semaphore uses
+    /// `Semantics::Lock`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if there is no semaphore set with the id `semid`
+    /// or if it can't be accessed by the current process.
+    #[cfg(unix)]
+    pub fn from_raw(semid: RawSemId) -> Result<Semaphore> {
+        let inner = unsafe { Inner::SystemV(imp::Semaphore::from_semid(semid)?) };
+        Ok(Semaphore { inner, semantics: Semantics::Lock })
+    }
+
+    /// Consumes this semaphore, returning its System V semaphore id.
+    ///
+    /// The semaphore itself is unaffected and can be opened again with
+    /// `from_raw`. Semaphores of other backends have no id, so -1 is returned
+    /// for them and they are closed.
+    #[cfg(unix)]
+    pub fn into_raw(self) -> RawSemId {
+        self.as_raw_semid()
+    }
+
+    /// Returns the System V key of the semaphore named `name`.
+    ///
+    /// This is equivalent to `Semaphore::options().key_for(name)`, see that
+    /// function for how the key is derived.
+    #[cfg(unix)]
+    pub fn key_for(name: &str) -> Result<i32> {
+        Semaphore::options().key_for(name)
     }
 
     /// Acquire a resource of this semaphore.
     ///
     /// This function will block until a resource is available (a count > 0),
     /// and then decrement it and return.
-    pub fn acquire(&self) { unsafe { self.inner.wait() } }
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the semaphore could not be operated on. Errors
+    /// which a caller may want to recover from, such as the semaphore being
+    /// removed by another process, carry an `ipc::Error` describing them
+    /// which can be retrieved with `Error::from_io`.
+    pub fn acquire(&self) -> Result<()> {
+        unsafe { self.inner.wait(1, None, self.undo()).map(|_| ()) }
+    }
 
     /// Attempt to acquire a resource of this semaphore.
     ///
     /// This function is identical to `acquire` except that it will never
     /// blocked. This function returns `true` if a resource was acquired or
     /// `false` if one could not be acquired.
-    pub fn try_acquire(&self) -> bool { unsafe { self.inner.try_wait() } }
+    pub fn try_acquire(&self) -> Result<bool> {
+        unsafe { self.inner.try_wait(1, self.undo()) }
+    }
+
+    /// Attempt to acquire a resource of this semaphore, blocking for at most
+    /// `dur`.
+    ///
+    /// This function is identical to `acquire` except that it will give up
+    /// once `dur` has elapsed. This function returns `true` if a resource was
+    /// acquired or `false` if the timeout expired first.
+    pub fn acquire_timeout(&self, dur: Duration) -> Result<bool> {
+        let deadline = Instant::now().checked_add(dur);
+        unsafe { self.inner.wait(1, deadline, self.undo()) }
+    }
+
+    /// Attempt to acquire a resource of this semaphore, blocking until
+    /// `deadline` at the latest.
+    ///
+    /// This function is identical to `acquire_timeout` except that the limit
+    /// is expressed as an absolute point in time. This makes it easy to share
+    /// one overall deadline between several semaphores acquired in sequence.
+    /// A deadline in the past still makes one non-blocking attempt.
+    pub fn acquire_until(&self, deadline: Instant) -> Result<bool> {
+        unsafe { self.inner.wait(1, Some(deadline), self.undo()) }
+    }
+
+    /// Block until the count of this semaphore is zero.
+    ///
+    /// The count is left untouched, so this is the opposite of `acquire`: a
+    /// process waiting here is woken up once every resource has been taken.
+    /// Handing out one resource per outstanding task and waiting for zero
+    /// gives an "all workers done" signal, see `Latch`.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `acquire`, an error is returned if
+    /// the backend doesn't support waiting for zero. Only `Backend::SystemV`
+    /// on unix and `Backend::Futex` do.
+    pub fn wait_zero(&self) -> Result<()> {
+        unsafe { self.inner.wait_zero(None).map(|_| ()) }
+    }
+
+    /// Check whether the count of this semaphore is zero without blocking.
+    ///
+    /// This function is identical to `wait_zero` except that it returns
+    /// `false` instead of blocking if the count isn't zero.
+    pub fn try_wait_zero(&self) -> Result<bool> {
+        unsafe { self.inner.try_wait_zero() }
+    }
+
+    /// Block until the count of this semaphore is zero, for at most `dur`.
+    ///
+    /// This function is identical to `wait_zero` except that it will give up
+    /// once `dur` has elapsed, returning `false`.
+    pub fn wait_zero_timeout(&self, dur: Duration) -> Result<bool> {
+        let deadline = Instant::now().checked_add(dur);
+        unsafe { self.inner.wait_zero(deadline) }
+    }
+
+    /// Block until the count of this semaphore is zero, until `deadline` at
+    /// the latest.
+    ///
+    /// This function is identical to `wait_zero_timeout` except that the
+    /// limit is expressed as an absolute point in time.
+    pub fn wait_zero_until(&self, deadline: Instant) -> Result<bool> {
+        unsafe { self.inner.wait_zero(Some(deadline)) }
+    }
 
     /// Release a resource of this semaphore.
     ///
     /// This function will increment the count of this semaphore, waking up any
     /// waiters who would like the resource.
-    pub fn release(&self) { unsafe { self.inner.post() } }
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `acquire`, an error of kind
+    /// `ipc::ErrorKind::Overflow` is returned if the count would exceed the
+    /// largest value supported by the system.
+    pub fn release(&self) -> Result<()> {
+        unsafe { self.inner.post(1, self.undo()) }
+    }
+
+    /// Changes the permission bits of this semaphore.
+    ///
+    /// Only the `0o777` bits of `mode` are used. On unix the key file backing
+    /// the name of the semaphore is updated to match as well.
+    ///
+    /// # Errors
+    ///
+    /// Only the owner or creator of a semaphore may change its permissions.
+    /// This is not supported on windows.
+    pub fn set_permissions(&self, mode: u32) -> Result<()> {
+        unsafe { self.inner.set_permissions(mode) }
+    }
+
+    /// Changes the user and group owning this semaphore.
+    ///
+    /// # Errors
+    ///
+    /// Only the owner or creator of a semaphore may change its ownership. This
+    /// is not supported on windows.
+    pub fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        unsafe { self.inner.set_owner(uid, gid) }
+    }
+
+    /// Returns a snapshot of the current state of this semaphore.
+    ///
+    /// # Errors
+    ///
+    /// This is only supported for System V semaphores on unix; an error is
+    /// returned on other platforms or if the state could not be queried.
+    pub fn stats(&self) -> Result<SemaphoreStats> {
+        unsafe { self.inner.stats() }
+    }
+
+    /// Returns the current count of this semaphore.
+    ///
+    /// This is the same as `stats().map(|s| s.value())`, but is also
+    /// supported by `Backend::Posix` and `Backend::Futex`.
+    pub fn value(&self) -> Result<usize> {
+        unsafe { self.inner.value() }
+    }
 
     /// Access a resource of this semaphore in a constrained scope.
     ///
@@ -109,46 +529,1143 @@ impl Semaphore {
     /// guard structure which will release the resource when it falls out of
     /// scope. For a mutex-like semaphore, it is recommended to use this method
     /// rather than the `acquire` or `release` methods.
-    pub fn access(&self) -> Guard {
-        self.acquire();
-        Guard { sem: self }
+    ///
+    /// The returned guard always uses `Semantics::Lock`.
+    pub fn access(&self) -> Result<Guard<'_>> {
+        unsafe { self.inner.wait(1, None, true)?; }
+        Ok(Guard { sem: self, cnt: 1 })
     }
 
     /// Attempt to access a resource of this semaphore.
     ///
     /// This function is identical to `access` except that it will never block.
-    pub fn try_access(&self) -> Option<Guard> {
-        if self.try_acquire() {
-            Some(Guard { sem: self })
+    pub fn try_access(&self) -> Result<Option<Guard<'_>>> {
+        let acquired = unsafe { self.inner.try_wait(1, true)? };
+        Ok(self.guard(1, acquired))
+    }
+
+    /// Attempt to access a resource of this semaphore, blocking for at most
+    /// `dur`.
+    ///
+    /// This function is identical to `access` except that it will return
+    /// `None` if a resource could not be acquired before the timeout expired.
+    pub fn access_timeout(&self, dur: Duration) -> Result<Option<Guard<'_>>> {
+        let deadline = Instant::now().checked_add(dur);
+        let acquired = unsafe { self.inner.wait(1, deadline, true)? };
+        Ok(self.guard(1, acquired))
+    }
+
+    /// Attempt to access a resource of this semaphore, blocking until
+    /// `deadline` at the latest.
+    ///
+    /// This function is identical to `access_timeout` except that the limit
+    /// is expressed as an absolute point in time.
+    pub fn access_until(&self, deadline: Instant) -> Result<Option<Guard<'_>>> {
+        let acquired = unsafe { self.inner.wait(1, Some(deadline), true)? };
+        Ok(self.guard(1, acquired))
+    }
+
+    /// Acquire `n` resources of this semaphore at once.
+    ///
+    /// This function will block until at least `n` resources are available
+    /// and then decrement the count by `n`. On unix the whole amount is taken
+    /// in one atomic step, so a process never sits on a partial allocation
+    /// while waiting for the rest. Acquiring 0 resources returns immediately.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `acquire`, on unix an error of kind
+    /// `ipc::ErrorKind::Overflow` is returned if `n` is larger than
+    /// `i16::MAX`, the largest adjustment the system can make at once.
+    pub fn acquire_many(&self, n: usize) -> Result<()> {
+        unsafe { self.inner.wait(n, None, self.undo()).map(|_| ()) }
+    }
+
+    /// Attempt to acquire `n` resources of this semaphore at once.
+    ///
+    /// This function is identical to `acquire_many` except that it will never
+    /// block. Either all `n` resources are acquired and `true` is returned, or
+    /// none are and `false` is returned.
+    pub fn try_acquire_many(&self, n: usize) -> Result<bool> {
+        unsafe { self.inner.try_wait(n, self.undo()) }
+    }
+
+    /// Release `n` resources of this semaphore at once.
+    ///
+    /// This function will increment the count of this semaphore by `n`, waking
+    /// up any waiters who would like the resources.
+    pub fn release_many(&self, n: usize) -> Result<()> {
+        unsafe { self.inner.post(n, self.undo()) }
+    }
+
+    /// Access `n` resources of this semaphore in a constrained scope.
+    ///
+    /// This function is identical to `access` except that `n` resources are
+    /// acquired at once as in `acquire_many`, and all of them are released
+    /// when the returned guard falls out of scope.
+    pub fn access_many(&self, n: usize) -> Result<Guard<'_>> {
+        unsafe { self.inner.wait(n, None, true)?; }
+        Ok(Guard { sem: self, cnt: n })
+    }
+
+    /// Attempt to access `n` resources of this semaphore.
+    ///
+    /// This function is identical to `access_many` except that it will never
+    /// block.
+    pub fn try_access_many(&self, n: usize) -> Result<Option<Guard<'_>>> {
+        let acquired = unsafe { self.inner.try_wait(n, true)? };
+        Ok(self.guard(n, acquired))
+    }
+
+    /// Returns the semantics used by `acquire` and `release` on this handle.
+    pub fn semantics(&self) -> Semantics { self.semantics }
+
+    fn undo(&self) -> bool { self.semantics == Semantics::Lock }
+
+    fn guard(&self, cnt: usize, acquired: bool) -> Option<Guard<'_>> {
+        if acquired {
+            Some(Guard { sem: self, cnt })
         } else {
             None
         }
     }
 }
 
+impl OpenOptions {
+    /// Creates a blank new set of options, equivalent to
+    /// `Semaphore::options()`.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            create: true,
+            create_new: false,
+            initial: 0,
+            mode: 0o666,
+            per_user: false,
+            dir: None,
+            namespace: None,
+            semantics: Semantics::Lock,
+            backend: Backend::SystemV,
+            init_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets whether the semaphore should be created if it doesn't exist.
+    ///
+    /// When set to `false` the semaphore must already exist, and opening it
+    /// fails with an error of kind `NotFound` otherwise. This defaults to
+    /// `true`.
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    /// Sets whether a new semaphore must be created.
+    ///
+    /// When set to `true` opening fails with an error of kind `AlreadyExists`
+    /// if a semaphore with the same name already exists, guaranteeing that the
+    /// returned semaphore has the count given to `initial`. If set, `create` is
+    /// ignored. This defaults to `false`.
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Sets the initial count of the semaphore if it is created.
+    ///
+    /// This has no effect when an existing semaphore is opened. This defaults
+    /// to 0.
+    pub fn initial(&mut self, cnt: usize) -> &mut OpenOptions {
+        self.initial = cnt;
+        self
+    }
+
+    /// Sets the permission bits of the semaphore if it is created.
+    ///
+    /// Only the `0o777` bits are used, and the key file backing the name of
+    /// the semaphore on unix is created with the same read/write bits. This
+    /// has no effect when an existing semaphore is opened, or on windows. This
+    /// defaults to `0o666`, letting any local user access the semaphore.
+    pub fn mode(&mut self, mode: u32) -> &mut OpenOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether the semaphore is private to the current user.
+    ///
+    /// On unix the names of System V semaphores are backed by key files in a
+    /// directory, see `dir`. By default this directory is shared by all
+    /// users, so semaphores of the same name opened by different users are
+    /// the same semaphore. When set to `true` the key files are kept in a
+    /// sibling directory suffixed with the current user id and only
+    /// accessible to that user instead, so the name refers to a different
+    /// semaphore for each user. This has no effect on
+    /// other backends or on windows. This defaults to `false`.
+    ///
+    /// Either directory is refused with an error of kind `PermissionDenied`
+    /// if another user could tamper with it: the shared directory must be
+    /// owned by the current user or root and be sticky if it's writable by
+    /// others, and the per-user directory must be owned by the current user
+    /// and inaccessible to everyone else. To share semaphores between users
+    /// the shared directory therefore has to be created by root, or be
+    /// configured with `dir`.
+    pub fn per_user(&mut self, per_user: bool) -> &mut OpenOptions {
+        self.per_user = per_user;
+        self
+    }
+
+    /// Sets the directory holding the key files which name System V
+    /// semaphores on unix.
+    ///
+    /// Processes only share a semaphore if they use the same directory, so
+    /// separate deployments on one host can keep their semaphores apart by
+    /// using different directories. The directory is created if necessary and
+    /// is subject to the same checks as described in `per_user`. This has no
+    /// effect on other backends or on windows. This defaults to the value of
+    /// the `IPC_RS_DIR` environment variable if it is set, and to `ipc-rs-sems`
+    /// in `env::temp_dir()` otherwise.
+    pub fn dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut OpenOptions {
+        self.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets a namespace which is prefixed to the names of semaphores.
+    ///
+    /// Semaphores opened with different namespaces are distinct even if they
+    /// have the same name, so for example parallel test suites can use common
+    /// names like "foo" without interfering with each other. The name is
+    /// prefixed with the namespace and a `.`, after the leading `/` of a POSIX
+    /// semaphore name. This defaults to the value of the `IPC_RS_NAMESPACE`
+    /// environment variable if it is set, and to no namespace otherwise.
+    pub fn namespace(&mut self, namespace: &str) -> &mut OpenOptions {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Sets the semantics used by `acquire` and `release` on the semaphore.
+    ///
+    /// Unlike the other options this also applies when an existing semaphore
+    /// is opened, as it only affects the returned handle. Guards returned from
+    /// `access` always use `Semantics::Lock`. This defaults to
+    /// `Semantics::Lock`.
+    pub fn semantics(&mut self, semantics: Semantics) -> &mut OpenOptions {
+        self.semantics = semantics;
+        self
+    }
+
+    /// Sets the kind of system semaphore used. This defaults to
+    /// `Backend::SystemV`.
+    pub fn backend(&mut self, backend: Backend) -> &mut OpenOptions {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets how long to wait for another process to finish initializing a
+    /// semaphore it has just created.
+    ///
+    /// Creating a semaphore and setting its initial count are two separate
+    /// steps on unix, so a process opening a semaphore at the same time as its
+    /// creator has to wait for the creator to finish. Opening fails with an
+    /// error of kind `TimedOut` if that takes longer than `dur`, which can
+    /// only happen if the creator is very slow or died in between the two
+    /// steps. This has no effect on windows. This defaults to 5 seconds.
+    pub fn init_timeout(&mut self, dur: Duration) -> &mut OpenOptions {
+        self.init_timeout = dur;
+        self
+    }
+
+    /// Opens the semaphore named `name` with the options specified by `self`.
+    ///
+    /// # Errors
+    ///
+    /// Along with any errors from the underlying system, an error of kind
+    /// `NotFound` is returned if `create` is `false` and the semaphore doesn't
+    /// exist, and an error of kind `AlreadyExists` is returned if
+    /// `create_new` is `true` and the semaphore already exists.
+    pub fn open(&self, name: &str) -> Result<Semaphore> {
+        let name = &self.name(name);
+        let inner = unsafe {
+            match self.backend {
+                Backend::SystemV => Inner::SystemV(imp::Semaphore::new(name, self)?),
+                #[cfg(unix)]
+                Backend::Posix => Inner::Posix(posix::Semaphore::new(name, self)?),
+                #[cfg(all(target_os = "linux",
+                          any(target_arch = "x86", target_arch = "x86_64",
+                              target_arch = "arm", target_arch = "aarch64")))]
+                Backend::Futex => Inner::Futex(futex::Semaphore::new(name, self)?),
+                #[cfg(not(unix))]
+                Backend::Posix => return Err(unsupported(self.backend)),
+                #[cfg(not(all(target_os = "linux",
+                              any(target_arch = "x86", target_arch = "x86_64",
+                                  target_arch = "arm", target_arch = "aarch64"))))]
+                Backend::Futex => return Err(unsupported(self.backend)),
+            }
+        };
+        Ok(Semaphore { inner, semantics: self.semantics })
+    }
+
+    /// Removes the semaphore named `name` from the system, using the backend
+    /// configured in `self`.
+    ///
+    /// See `Semaphore::unlink` for more information.
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        let name = &self.name(name);
+        unsafe {
+            match self.backend {
+                Backend::SystemV => imp::Semaphore::unlink(name, self),
+                #[cfg(unix)]
+                Backend::Posix => posix::Semaphore::unlink(name, self),
+                #[cfg(all(target_os = "linux",
+                          any(target_arch = "x86", target_arch = "x86_64",
+                              target_arch = "arm", target_arch = "aarch64")))]
+                Backend::Futex => futex::Semaphore::unlink(name, self),
+                #[cfg(not(unix))]
+                Backend::Posix => Err(unsupported(self.backend)),
+                #[cfg(not(all(target_os = "linux",
+                              any(target_arch = "x86", target_arch = "x86_64",
+                                  target_arch = "arm", target_arch = "aarch64"))))]
+                Backend::Futex => Err(unsupported(self.backend)),
+            }
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Creates a new semaphore without a name with the options specified by
+    /// `self`.
+    ///
+    /// See `Semaphore::anonymous` for more information. Only `initial`,
+    /// `mode` and `semantics` apply to anonymous semaphores.
+    ///
+    /// # Errors
+    ///
+    /// Anonymous semaphores are only supported by `Backend::SystemV`.
+    #[cfg(unix)]
+    pub fn create_anonymous(&self) -> Result<Semaphore> {
+        if self.backend != Backend::SystemV {
+            return Err(std::io::Error::other("only System V semaphores can be \
+                                              anonymous"))
+        }
+        let inner = unsafe { Inner::SystemV(imp::Semaphore::anonymous(self)?) };
+        Ok(Semaphore { inner, semantics: self.semantics })
+    }
+
+    /// Opens a semaphore passed to this process by its parent with
+    /// `Semaphore::pass_to`, using the semantics specified by `self`.
+    ///
+    /// # Errors
+    ///
+    /// An error of kind `NotFound` is returned if the environment variable
+    /// `var` is not set, and an error of kind `InvalidData` if it doesn't
+    /// hold a semaphore id. Otherwise the errors are those of
+    /// `Semaphore::from_raw`.
+    #[cfg(unix)]
+    pub fn open_inherited(&self, var: &str) -> Result<Semaphore> {
+        let value = env::var(var).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::NotFound,
+                                format!("no semaphore passed in `{}`", var))
+        })?;
+        let semid = value.parse().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                format!("invalid semaphore id `{}` in `{}`",
+                                        value, var))
+        })?;
+        let inner = unsafe { Inner::SystemV(imp::Semaphore::from_semid(semid)?) };
+        Ok(Semaphore { inner, semantics: self.semantics })
+    }
+
+    /// Returns the System V key of the semaphore named `name`, as it would be
+    /// opened with these options.
+    ///
+    /// This allows programs which don't use this crate to find the semaphore.
+    /// The key is derived as follows, which is version 1 of the scheme:
+    ///
+    /// 1. The name is prefixed with the namespace, if any, as described in
+    ///    `namespace`.
+    /// 2. The key file is named by the ASCII alphanumeric characters of the
+    ///    name, followed by `-v1-` and the 64-bit FNV-1a hash of the UTF-8
+    ///    bytes of the name as 16 lowercase hex digits. For example the name
+    ///    `foo` has the key file `foo-v1-dcb27518fed9d577`.
+    /// 3. The key file lives in the directory described in `dir`, or the one
+    ///    described in `per_user`.
+    /// 4. The key is `ftok(path, 'I')`.
+    ///
+    /// The semaphore set has 4 members. The first holds the count, and the
+    /// second is raised to 1 once the set has been initialized by its
+    /// creator. The last two hold the high and low 15 bits of the low 30 bits
+    /// of the FNV-1a hash of the path of the key file, with its directory
+    /// canonicalized, which is used to detect collisions as described in
+    /// `ErrorKind::Collision`.
+    ///
+    /// # Errors
+    ///
+    /// An error of kind `NotFound` is returned if the key file doesn't exist,
+    /// which is the case if the semaphore has never been created.
+    #[cfg(unix)]
+    pub fn key_for(&self, name: &str) -> Result<i32> {
+        unsafe { imp::Semaphore::key_for(&self.name(name), self) }
+    }
+
+    /// Returns the name actually passed to the backend for `name`, which is
+    /// prefixed by the namespace if there is one.
+    fn name(&self, name: &str) -> String {
+        let namespace = match self.namespace {
+            Some(ref namespace) => namespace.clone(),
+            None => match env::var("IPC_RS_NAMESPACE") {
+                Ok(ref namespace) if !namespace.is_empty() => namespace.clone(),
+                _ => return name.to_string(),
+            },
+        };
+        match name.strip_prefix('/') {
+            Some(name) => format!("/{}.{}", namespace, name),
+            None => format!("{}.{}", namespace, name),
+        }
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> OpenOptions { OpenOptions::new() }
+}
+
+impl SemaphoreStats {
+    /// The current count of the semaphore, i.e. the number of resources which
+    /// are free to be acquired.
+    pub fn value(&self) -> usize { self.value }
+
+    /// The number of processes blocked waiting for the count to increase.
+    pub fn waiters(&self) -> usize { self.waiters }
+
+    /// The number of processes blocked waiting for the count to become zero.
+    pub fn zero_waiters(&self) -> usize { self.zero_waiters }
+
+    /// The pid of the process which last operated on the semaphore, or 0 if no
+    /// operation has been performed yet.
+    pub fn last_pid(&self) -> u32 { self.last_pid }
+}
+
+#[cfg(unix)]
+impl AsRawSemId for Semaphore {
+    fn as_raw_semid(&self) -> RawSemId {
+        match self.inner {
+            Inner::SystemV(ref s) => s.semid(),
+            _ => -1,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl FromRawSemId for Semaphore {
+    fn from_raw_semid(semid: RawSemId) -> Result<Semaphore> {
+        Semaphore::from_raw(semid)
+    }
+}
+
+impl<'a> Guard<'a> {
+    /// Returns the number of resources this guard will release when dropped.
+    pub fn count(&self) -> usize { self.cnt }
+}
+
 impl<'a> Drop for Guard<'a> {
     fn drop(&mut self) {
-        unsafe { self.sem.inner.post() }
+        // There's nobody to report an error to here, and the only way for this
+        // to fail is for the semaphore to have been removed already.
+        let _ = unsafe { self.sem.inner.post(self.cnt, true) };
     }
 }
 
+/// The backend-specific implementation of a `Semaphore`.
+///
+/// Every backend implements the same set of methods, which are forwarded to
+/// through `backend!`.
+enum Inner {
+    SystemV(imp::Semaphore),
+    #[cfg(unix)]
+    Posix(posix::Semaphore),
+    #[cfg(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64")))]
+    Futex(futex::Semaphore),
+}
+
+macro_rules! backend {
+    ($inner:expr, $s:ident => $e:expr) => (match $inner {
+        Inner::SystemV($s) => $e,
+        #[cfg(unix)]
+        Inner::Posix($s) => $e,
+        #[cfg(all(target_os = "linux",
+                  any(target_arch = "x86", target_arch = "x86_64",
+                      target_arch = "arm", target_arch = "aarch64")))]
+        Inner::Futex($s) => $e,
+    })
+}
+
+impl Inner {
+    unsafe fn remove(self) -> Result<()> { backend!(self, s => s.remove()) }
+
+    unsafe fn wait(&self, n: usize, deadline: Option<Instant>,
+                   undo: bool) -> Result<bool> {
+        backend!(self, s => s.wait(n, deadline, undo))
+    }
+
+    unsafe fn try_wait(&self, n: usize, undo: bool) -> Result<bool> {
+        backend!(self, s => s.try_wait(n, undo))
+    }
+
+    unsafe fn post(&self, n: usize, undo: bool) -> Result<()> {
+        backend!(self, s => s.post(n, undo))
+    }
+
+    unsafe fn wait_zero(&self, deadline: Option<Instant>) -> Result<bool> {
+        backend!(self, s => s.wait_zero(deadline))
+    }
+
+    unsafe fn try_wait_zero(&self) -> Result<bool> {
+        backend!(self, s => s.try_wait_zero())
+    }
+
+    unsafe fn set_permissions(&self, mode: u32) -> Result<()> {
+        backend!(self, s => s.set_permissions(mode))
+    }
+
+    unsafe fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        backend!(self, s => s.set_owner(uid, gid))
+    }
+
+    unsafe fn stats(&self) -> Result<SemaphoreStats> {
+        backend!(self, s => s.stats())
+    }
+
+    unsafe fn value(&self) -> Result<usize> {
+        backend!(self, s => s.value())
+    }
+}
+
+#[cfg(not(all(unix, target_os = "linux",
+            any(target_arch = "x86", target_arch = "x86_64",
+                target_arch = "arm", target_arch = "aarch64"))))]
+fn unsupported(backend: Backend) -> std::io::Error {
+    std::io::Error::other(format!("{:?} semaphores are not supported on this \
+                                   platform", backend))
+}
+
+#[cfg(unix)] mod barrier;
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+mod condvar;
+mod error;
+mod hash;
+#[cfg(unix)] mod latch;
+#[cfg(unix)] mod mutex;
+#[cfg(unix)] mod posix;
+#[cfg(unix)] mod rwlock;
+#[cfg(unix)] mod set;
+#[cfg(unix)] mod shm;
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+mod futex;
+
 #[cfg(unix)] #[path = "unix.rs"] mod imp;
 #[cfg(windows)] #[path = "windows.rs"] mod imp;
 
 #[cfg(test)]
 mod tests {
-    use Semaphore;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[cfg(unix)]
+    use libc;
+
+    use {Error, Semantics, Semaphore};
 
     #[test]
     fn smoke() {
         let s = Semaphore::new("smoke", 1).unwrap();
-        drop(s.access());
+        drop(s.access().unwrap());
+        {
+            let _g = s.access().unwrap();
+            assert!(s.try_access().unwrap().is_none());
+            assert!(!s.try_acquire().unwrap());
+        }
+        assert!(s.try_access().unwrap().is_some());
+    }
+
+    #[test]
+    fn timeout() {
+        let s = Semaphore::new("timeout", 1).unwrap();
+        let g = s.access_timeout(Duration::from_millis(10)).unwrap().unwrap();
+        let start = Instant::now();
+        assert!(!s.acquire_timeout(Duration::from_millis(50)).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(s.access_timeout(Duration::from_millis(0)).unwrap().is_none());
+        drop(g);
+        assert!(s.access_timeout(Duration::from_millis(10)).unwrap().is_some());
+    }
+
+    #[test]
+    fn deadline() {
+        let s1 = Semaphore::new("deadline1", 1).unwrap();
+        let s2 = Semaphore::new("deadline2", 0).unwrap();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        let g = s1.access_until(deadline).unwrap().unwrap();
+        assert!(s2.access_until(deadline).unwrap().is_none());
+        assert!(Instant::now() >= deadline);
+        assert!(!s1.acquire_until(deadline).unwrap());
+        drop(g);
+        assert!(s1.acquire_until(start).unwrap());
+        s1.release().unwrap();
+    }
+
+    #[test]
+    fn many() {
+        let s = Semaphore::new("many", 16).unwrap();
+        {
+            let g = s.access_many(4).unwrap();
+            assert_eq!(g.count(), 4);
+            assert!(s.try_access_many(13).unwrap().is_none());
+            let g2 = s.try_access_many(12).unwrap().unwrap();
+            assert_eq!(g2.count(), 12);
+            assert!(!s.try_acquire().unwrap());
+        }
+        assert!(s.try_acquire_many(16).unwrap());
+        assert!(s.try_acquire_many(0).unwrap());
+        s.release_many(16).unwrap();
+        assert!(s.try_access_many(16).unwrap().is_some());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stats() {
+        let s = Semaphore::new("stats", 3).unwrap();
+        let _g = s.access().unwrap();
+        let stats = s.stats().unwrap();
+        assert_eq!(stats.value(), 2);
+        assert_eq!(stats.waiters(), 0);
+        assert_eq!(stats.zero_waiters(), 0);
+        assert_eq!(stats.last_pid(), unsafe { libc::getpid() } as u32);
+        assert_eq!(s.value().unwrap(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn remove() {
+        let s = Semaphore::new("remove", 1).unwrap();
+        s.remove().unwrap();
+        let err = Semaphore::unlink("remove").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let s = Semaphore::new("remove", 2).unwrap();
+        assert_eq!(s.value().unwrap(), 2);
+        Semaphore::unlink("remove").unwrap();
+        assert!(s.remove().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn errors() {
+        let _ = Semaphore::unlink("errors");
+        let s = Semaphore::new("errors", 0).unwrap();
+        let err = s.release_many(40000).unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Overflow);
+        s.release_many(32767).unwrap();
+        let err = s.release().unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Overflow);
+        s.remove().unwrap();
+
+        let s = Arc::new(Semaphore::new("errors", 0).unwrap());
+        let s2 = s.clone();
+        let t = thread::spawn(move || s2.acquire());
+        thread::sleep(Duration::from_millis(50));
+        Semaphore::unlink("errors").unwrap();
+        let err = t.join().unwrap().unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Removed);
+        let err = s.try_acquire().unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Removed);
+        assert!(s.release().is_err());
+    }
+
+    #[test]
+    fn semantics() {
+        let s = Semaphore::options().semantics(Semantics::Signal).initial(1)
+                                    .open("semantics").unwrap();
+        assert_eq!(s.semantics(), Semantics::Signal);
+        assert_eq!(Semaphore::new("semantics", 1).unwrap().semantics(),
+                   Semantics::Lock);
+        drop(s.access().unwrap());
+        s.acquire().unwrap();
+        s.release().unwrap();
+        assert!(s.try_access().unwrap().is_some());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn posix() {
+        use std::path::Path;
+        use Backend;
+
+        let mut opts = Semaphore::options();
+        opts.backend(Backend::Posix);
+        let _ = opts.unlink("/ipc-rs-posix");
+        let s = opts.create_new(true).initial(2).open("/ipc-rs-posix").unwrap();
+        if cfg!(target_os = "linux") {
+            assert!(Path::new("/dev/shm/sem.ipc-rs-posix").exists());
+            assert_eq!(s.value().unwrap(), 2);
+        }
+        assert!(s.stats().is_err());
+        {
+            let _g = s.access_many(2).unwrap();
+            assert!(!s.try_acquire().unwrap());
+            assert!(!s.acquire_timeout(Duration::from_millis(10)).unwrap());
+        }
+        let s2 = opts.create_new(false).create(false).open("/ipc-rs-posix").unwrap();
+        assert!(s2.try_acquire_many(2).unwrap());
+        assert!(s.try_access().unwrap().is_none());
+        s2.release_many(2).unwrap();
+        s.remove().unwrap();
+        assert_eq!(opts.unlink("/ipc-rs-posix").unwrap_err().kind(),
+                   ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64")))]
+    fn futex() {
+        use Backend;
+
+        let mut opts = Semaphore::options();
+        opts.backend(Backend::Futex);
+        let _ = opts.unlink("futex");
+        let s = Arc::new(opts.create_new(true).initial(1).open("futex").unwrap());
+        assert_eq!(s.value().unwrap(), 1);
+        assert!(s.stats().is_err());
+        {
+            let _g = s.access().unwrap();
+            assert!(!s.try_acquire().unwrap());
+            assert!(!s.acquire_timeout(Duration::from_millis(10)).unwrap());
+        }
+
+        // Hammer the semaphore from a few threads, each through its own
+        // mapping, to make sure sleeping and waking never loses a release.
+        let threads = (0..4).map(|_| {
+            let s = opts.create_new(false).create(false).open("futex").unwrap();
+            thread::spawn(move || {
+                for _ in 0..10000 {
+                    drop(s.access().unwrap());
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.value().unwrap(), 1);
+
+        let err = s.release_many(libc::c_int::MAX as usize).unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Overflow);
+        Arc::try_unwrap(s).ok().unwrap().remove().unwrap();
+        assert_eq!(opts.unlink("futex").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn options() {
+        let _ = Semaphore::unlink("options");
+        let err = Semaphore::options().create(false).open("options").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let s = Semaphore::options().create_new(true).initial(2).open("options")
+                                    .unwrap();
+        assert!(s.try_acquire_many(2).unwrap());
+        s.release_many(2).unwrap();
+        let err = Semaphore::options().create_new(true).open("options").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        let s2 = Semaphore::options().create(false).initial(5).open("options")
+                                     .unwrap();
+        assert!(!s2.try_acquire_many(3).unwrap());
+        assert!(s2.try_acquire_many(2).unwrap());
+        s2.release_many(2).unwrap();
+        s.remove().unwrap();
+    }
+
+    #[test]
+    fn namespace() {
+        let mut a = Semaphore::options();
+        a.namespace("a").create_new(true).initial(1);
+        let mut b = Semaphore::options();
+        b.namespace("b").create_new(true).initial(2);
+        let _ = a.unlink("namespace");
+        let _ = b.unlink("namespace");
+
+        let sa = a.open("namespace").unwrap();
+        let sb = b.open("namespace").unwrap();
+        assert!(!sa.try_acquire_many(2).unwrap());
+        assert!(sb.try_acquire_many(2).unwrap());
+        sb.release_many(2).unwrap();
+        assert_eq!(Semaphore::options().create(false).open("namespace")
+                                       .err().unwrap().kind(),
+                   ErrorKind::NotFound);
+        sa.remove().unwrap();
+        sb.remove().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn key_for() {
+        let _ = Semaphore::unlink("key_for");
+        let err = Semaphore::key_for("key_for").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let s = Semaphore::new("key_for", 1).unwrap();
+        let key = Semaphore::key_for("key_for").unwrap();
+        assert_eq!(Semaphore::options().namespace("other").key_for("key_for")
+                                       .unwrap_err().kind(),
+                   ErrorKind::NotFound);
+        s.remove().unwrap();
+        assert!(key != -1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn anonymous() {
+        use std::env;
+        use std::process::Command;
+
+        let s = Semaphore::anonymous(2).unwrap();
+        let mut cmd = Command::new("true");
+        s.pass_to(&mut cmd, "IPC_RS_TEST_ANONYMOUS").unwrap();
+        let (_, value) = cmd.get_envs().next().unwrap();
+        env::set_var("IPC_RS_TEST_ANONYMOUS", value.unwrap());
+
+        let s2 = Semaphore::from_inherited("IPC_RS_TEST_ANONYMOUS").unwrap();
+        assert!(s2.try_acquire_many(2).unwrap());
+        assert!(!s.try_acquire().unwrap());
+        s2.release_many(2).unwrap();
+        assert_eq!(s.value().unwrap(), 2);
+        s.remove().unwrap();
+        assert!(Semaphore::from_inherited("IPC_RS_TEST_ANONYMOUS").is_err());
+
+        env::set_var("IPC_RS_TEST_ANONYMOUS", "foo");
+        assert_eq!(Semaphore::from_inherited("IPC_RS_TEST_ANONYMOUS")
+                       .err().unwrap().kind(),
+                   ErrorKind::InvalidData);
+        env::remove_var("IPC_RS_TEST_ANONYMOUS");
+        assert_eq!(Semaphore::from_inherited("IPC_RS_TEST_ANONYMOUS")
+                       .err().unwrap().kind(),
+                   ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn raw() {
+        use {AsRawSemId, Backend, FromRawSemId};
+
+        let s = Semaphore::new("raw", 1).unwrap();
+        let semid = s.as_raw_semid();
+        let s2 = Semaphore::from_raw_semid(semid).unwrap();
+        assert_eq!(s2.as_raw_semid(), semid);
         {
-            let _g = s.access();
-            assert!(s.try_access().is_none());
-            assert!(!s.try_acquire());
+            let _g = s2.access().unwrap();
+            assert!(!s.try_acquire().unwrap());
         }
-        assert!(s.try_access().is_some());
+        assert_eq!(s2.into_raw(), semid);
+        let s3 = Semaphore::from_raw(semid).unwrap();
+        s.remove().unwrap();
+        assert!(s3.try_acquire().is_err());
+        assert!(Semaphore::from_raw(semid).is_err());
+        let err = Semaphore::from_raw(-1).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert!(Error::from_io(&err).is_none());
+
+        let mut opts = Semaphore::options();
+        opts.backend(Backend::Posix);
+        let _ = opts.unlink("/ipc-rs-raw");
+        let s = opts.open("/ipc-rs-raw").unwrap();
+        assert_eq!(s.as_raw_semid(), -1);
+        s.remove().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set() {
+        use std::io::ErrorKind;
+        use {Op, SemaphoreSet};
+
+        let _ = Semaphore::unlink("set");
+        let s = SemaphoreSet::new("set", &[1, 2]).unwrap();
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.value(1).unwrap(), 2);
+
+        s.apply(&[Op::acquire(0, 1), Op::acquire(1, 2)]).unwrap();
+        assert_eq!(s.value(0).unwrap(), 0);
+        assert_eq!(s.value(1).unwrap(), 0);
+
+        // nothing happens unless everything can
+        s.apply(&[Op::release(1, 1)]).unwrap();
+        assert!(!s.try_apply(&[Op::acquire(1, 1), Op::acquire(0, 1)]).unwrap());
+        assert_eq!(s.value(1).unwrap(), 1);
+        assert!(!s.apply_timeout(&[Op::acquire(0, 1)],
+                                 Duration::from_millis(10)).unwrap());
+
+        let s2 = SemaphoreSet::new("set", &[0, 0]).unwrap();
+        s2.apply(&[Op::release(0, 1), Op::release(1, 1)]).unwrap();
+        assert!(s.try_apply(&[Op::acquire(0, 1), Op::acquire(1, 2)]).unwrap());
+        assert!(s.try_apply(&[Op::wait_zero(0), Op::acquire(1, 0)]).unwrap());
+        s2.apply(&[Op::release(1, 1)]).unwrap();
+        assert!(!s.try_apply(&[Op::wait_zero(1), Op::release(0, 1)]).unwrap());
+        assert_eq!(s.value(0).unwrap(), 0);
+
+        let err = s.apply(&[Op::acquire(2, 1)]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = s.apply(&[Op::release(0, 1 << 16)]).unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Overflow);
+        let err = s.apply(&[Op::acquire(0, usize::MAX)]).unwrap_err();
+        assert_eq!(Error::from_io(&err).unwrap().kind(), ::ErrorKind::Overflow);
+        assert!(SemaphoreSet::new("set", &[0]).is_err());
+        assert!(Semaphore::new("set", 0).is_err());
+        s.remove().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_zero() {
+        use Backend;
+
+        let mut opts = Semaphore::options();
+        let mut backends = vec![Backend::SystemV];
+        if cfg!(all(target_os = "linux",
+                    any(target_arch = "x86", target_arch = "x86_64",
+                        target_arch = "arm", target_arch = "aarch64"))) {
+            backends.push(Backend::Futex);
+        }
+        for backend in backends {
+            opts.backend(backend).semantics(Semantics::Signal);
+            let _ = opts.unlink("wait_zero");
+            let s = Arc::new(opts.initial(2).open("wait_zero").unwrap());
+            assert!(!s.try_wait_zero().unwrap());
+            assert!(!s.wait_zero_timeout(Duration::from_millis(10)).unwrap());
+
+            let s2 = s.clone();
+            let t = thread::spawn(move || s2.wait_zero().unwrap());
+            s.acquire_many(2).unwrap();
+            t.join().unwrap();
+            assert!(s.try_wait_zero().unwrap());
+            s.release().unwrap();
+            assert!(!s.try_wait_zero().unwrap());
+            match Arc::try_unwrap(s) {
+                Ok(s) => s.remove().unwrap(),
+                Err(..) => panic!(),
+            }
+        }
+
+        let mut opts = Semaphore::options();
+        opts.backend(Backend::Posix);
+        let _ = opts.unlink("/ipc-rs-wait-zero");
+        let s = opts.open("/ipc-rs-wait-zero").unwrap();
+        assert!(s.try_wait_zero().is_err());
+        s.remove().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mutex() {
+        use std::env;
+        use std::io::ErrorKind;
+        use {Backend, Mutex};
+
+        let _ = Mutex::<[u32; 2]>::unlink("mutex");
+        let m = Mutex::new("mutex", [1u32, 2]).unwrap();
+        assert_eq!(*m.lock().unwrap(), [1, 2]);
+
+        // Each thread goes through its own mapping and semaphore handle.
+        let threads = (0..4).map(|_| {
+            let m = Mutex::new("mutex", [0u32, 0]).unwrap();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let mut g = m.lock().unwrap();
+                    g[0] += 1;
+                    g[1] = g[0] * 2;
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock().unwrap(), [4001, 8002]);
+
+        {
+            let _g = m.lock().unwrap();
+            assert!(m.try_lock().unwrap().is_none());
+            assert!(m.lock_timeout(Duration::from_millis(10)).unwrap().is_none());
+        }
+        assert!(m.try_lock().unwrap().is_some());
+
+        let err = Mutex::new("mutex", 0u8).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // another key directory has its own value, and the lock is always a
+        // System V semaphore
+        let mut opts = Semaphore::options();
+        opts.dir(env::temp_dir().join("ipc-rs-mutex-dir"));
+        opts.backend(Backend::Posix);
+        let other = Mutex::with_options(&opts, "mutex", 0u8).unwrap();
+        assert_eq!(*other.lock().unwrap(), 0);
+        drop(other);
+        let mut sysv = opts.clone();
+        sysv.backend(Backend::SystemV).create(false);
+        assert!(sysv.open("mutex").is_ok());
+        Mutex::<u8>::unlink_with_options(&opts, "mutex").unwrap();
+        assert!(Mutex::<u8>::unlink_with_options(&opts, "mutex").is_err());
+        m.remove().unwrap();
+        assert!(Mutex::<[u32; 2]>::unlink("mutex").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rwlock() {
+        use RwLock;
+
+        let _ = RwLock::<u32>::unlink("rwlock");
+        let l = RwLock::new("rwlock", 1u32).unwrap();
+        {
+            let r1 = l.read().unwrap();
+            let r2 = l.try_read().unwrap().unwrap();
+            assert_eq!((*r1, *r2), (1, 1));
+            assert!(l.try_write().unwrap().is_none());
+            assert!(l.write_timeout(Duration::from_millis(10)).unwrap().is_none());
+        }
+        {
+            let mut w = l.write().unwrap();
+            *w = 2;
+            assert!(l.try_read().unwrap().is_none());
+            assert!(l.read_timeout(Duration::from_millis(10)).unwrap().is_none());
+            assert!(l.try_write().unwrap().is_none());
+        }
+        assert_eq!(*l.read().unwrap(), 2);
+
+        // A writer waiting behind a reader holds off new readers which prefer
+        // writers, but not the others.
+        let opts = Semaphore::options();
+        let l = Arc::new(RwLock::with_options(&opts, "rwlock", 0, true).unwrap());
+        let r = l.read().unwrap();
+        assert!(l.write_timeout(Duration::from_millis(10)).unwrap().is_none());
+        assert!(l.try_read().unwrap().is_some());
+
+        let l2 = l.clone();
+        let t = thread::spawn(move || *l2.write().unwrap() = 3);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while l.try_read().unwrap().is_some() {
+            assert!(Instant::now() < deadline);
+            thread::yield_now();
+        }
+        let other = RwLock::new("rwlock", 0u32).unwrap();
+        assert_eq!(*other.try_read().unwrap().unwrap(), 2);
+        drop(r);
+        t.join().unwrap();
+        assert_eq!(*l.read().unwrap(), 3);
+
+        assert!(RwLock::new("rwlock", 0u8).is_err());
+        other.remove().unwrap();
+    }
+
+    #[cfg(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64")))]
+    #[test]
+    fn condvar() {
+        use {Condvar, Mutex};
+
+        let _ = Mutex::<u32>::unlink("condvar");
+        let _ = Condvar::unlink("condvar");
+        let m = Mutex::new("condvar", 0u32).unwrap();
+        let c = Condvar::new("condvar").unwrap();
+
+        let g = m.lock().unwrap();
+        let (g, res) = c.wait_timeout(g, Duration::from_millis(10)).unwrap();
+        assert!(res.timed_out());
+        drop(g);
+
+        // Each thread waits through its own handles until the main thread
+        // has bumped the value, and then acknowledges.
+        let threads = (0..4).map(|_| {
+            let m = Mutex::new("condvar", 0u32).unwrap();
+            let c = Condvar::new("condvar").unwrap();
+            thread::spawn(move || {
+                let mut g = m.lock().unwrap();
+                *g += 1;
+                c.notify_all().unwrap();
+                while *g < 100 {
+                    g = c.wait(g).unwrap();
+                }
+                *g += 1;
+            })
+        }).collect::<Vec<_>>();
+        let mut g = m.lock().unwrap();
+        while *g < 4 {
+            g = c.wait(g).unwrap();
+        }
+        *g = 100;
+        c.notify_one().unwrap();
+        c.notify_all().unwrap();
+        drop(g);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*m.lock().unwrap(), 104);
+
+        c.remove().unwrap();
+        m.remove().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn barrier() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering::SeqCst;
+        use Barrier;
+
+        const N: usize = 4;
+        const GENERATIONS: usize = 20;
+
+        let _ = Barrier::unlink("barrier");
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let leaders = Arc::new(AtomicUsize::new(0));
+
+        // Every thread checks that all of its generation arrived before it
+        // got through, through its own handle.
+        let threads = (0..N).map(|_| {
+            let b = Barrier::new("barrier", N).unwrap();
+            let (arrived, leaders) = (arrived.clone(), leaders.clone());
+            thread::spawn(move || {
+                for gen in 0..GENERATIONS {
+                    arrived.fetch_add(1, SeqCst);
+                    if b.wait().unwrap().is_leader() {
+                        leaders.fetch_add(1, SeqCst);
+                    }
+                    assert!(arrived.load(SeqCst) >= (gen + 1) * N);
+                }
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(leaders.load(SeqCst), GENERATIONS);
+
+        let err = Barrier::new("barrier", N + 1).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Barrier::new("barrier", N).unwrap().remove().unwrap();
+
+        let b = Barrier::new("barrier", 0).unwrap();
+        assert!(b.wait().unwrap().is_leader());
+        assert!(b.wait().unwrap().is_leader());
+        b.remove().unwrap();
+
+        let mut opts = Semaphore::options();
+        opts.namespace("barrier-ns");
+        Barrier::with_options(&opts, "barrier", 1).unwrap();
+        Barrier::unlink_with_options(&opts, "barrier").unwrap();
+        assert!(Barrier::unlink_with_options(&opts, "barrier").is_err());
     }
 
     #[test]
@@ -0,0 +1,347 @@
+//! A mutex protecting data in shared memory
+//!
+//! The data lives in a named shared memory region, behind a small header, and
+//! is guarded by a semaphore of the same name. Whoever first holds the lock
+//! and finds the header uninitialized stores the initial value, so there is
+//! no separate initialization step to race on.
+//!
+//! The semaphore releases the lock when its holder exits, but that alone
+//! would leave the next owner none the wiser. So the holder also records its
+//! pid in the header, and only clears it again when unlocking normally. Any
+//! owner finding a pid there knows that its predecessor died.
+
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::process;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::{Duration, Instant};
+
+use shm::{self, Shm};
+use {Backend, Guard, LockError, LockResult, OpenOptions, Semantics, Semaphore,
+     TryLockResult};
+
+/// Types which can be shared with other processes by copying their bytes.
+///
+/// A value placed in shared memory is read by processes with their own
+/// address spaces, so it must not contain pointers or references, and every
+/// process must agree on its layout. This is implemented for the integer and
+/// floating point types and arrays of them.
+///
+/// # Safety
+///
+/// Implementors must be plain data with no pointers, references or handles,
+/// and should be `#[repr(C)]` so that every program sharing them lays them
+/// out the same way. Every bit pattern must be a valid value, as the memory
+/// starts out zeroed and may be written by any process, which is why `bool`
+/// and `char` are not `Pod`.
+pub unsafe trait Pod: Copy + Send + 'static {}
+
+macro_rules! pod {
+    ($($t:ty)*) => ($(unsafe impl Pod for $t {})*)
+}
+
+pod! { () u8 u16 u32 u64 usize i8 i16 i32 i64 isize f32 f64 }
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A mutual exclusion primitive protecting a value shared between processes.
+///
+/// The value is stored in shared memory rather than in the `Mutex` itself, so
+/// every process opening a mutex with the same name sees the same value. The
+/// lock is a `Semaphore` using `Semantics::Lock`, so it is released if a
+/// process exits while holding it.
+///
+/// The mutex is robust: whoever locks it next is told that the previous owner
+/// died through `LockError::OwnerDied`, which still hands out a guard so that
+/// the possibly half-written value can be repaired. This is similar to
+/// poisoning in `std::sync::Mutex`, except that a thread panicking while
+/// holding the lock does not count.
+///
+/// Like semaphores, mutexes persist until they are removed with `remove` or
+/// `unlink`.
+///
+/// # Example
+///
+/// ```
+/// use ipc::Mutex;
+///
+/// let counter = Mutex::new("mutex-example", 0u64).unwrap();
+/// *counter.lock().unwrap() += 1;
+/// assert!(*counter.lock().unwrap() >= 1);
+/// ```
+pub struct Mutex<T: Pod> {
+    sem: Semaphore,
+    value: Value<T>,
+}
+
+/// An RAII guard giving access to the value of a `Mutex`, unlocking it when
+/// dropped.
+pub struct MutexGuard<'a, T: Pod + 'a> {
+    mutex: &'a Mutex<T>,
+    _guard: Guard<'a>,
+}
+
+/// The header in front of the value, recording which type it holds.
+#[repr(C)]
+struct Header {
+    /// `UNINIT` until the first process to take the lock has stored the
+    /// initial value, then `READY`.
+    state: AtomicU32,
+    size: AtomicU32,
+    align: AtomicU32,
+    /// The pid of the process holding the lock, or 0 if the last owner
+    /// unlocked it normally.
+    owner: AtomicU32,
+}
+
+const UNINIT: u32 = 0;
+const READY: u32 = 1;
+
+impl<T: Pod> Mutex<T> {
+    /// Opens the mutex named `name`, creating it with the value `value` if it
+    /// doesn't already exist.
+    ///
+    /// This is equivalent to `Mutex::with_options(&Semaphore::options(), name,
+    /// value)`.
+    pub fn new(name: &str, value: T) -> Result<Mutex<T>> {
+        Mutex::with_options(&OpenOptions::new(), name, value)
+    }
+
+    /// Opens the mutex named `name` with the options specified by `opts`.
+    ///
+    /// `value` is only stored if the mutex holds no value yet, otherwise it is
+    /// ignored. The `initial` count, semantics and backend of `opts` are
+    /// ignored, as the lock always starts out unlocked and is a System V
+    /// semaphore using `Semantics::Lock`. That's the only kind of semaphore
+    /// which is released when its holder dies.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `OpenOptions::open`, an error of
+    /// kind `InvalidData` is returned if the existing mutex holds a value of
+    /// a different size or alignment than `T`.
+    pub fn with_options(opts: &OpenOptions, name: &str,
+                        value: T) -> Result<Mutex<T>> {
+        let mut opts = opts.clone();
+        opts.initial(1).semantics(Semantics::Lock).backend(Backend::SystemV);
+        let sem = opts.open(name)?;
+        let mutex = Mutex { sem, value: Value::open(&opts, name, "mutex")? };
+
+        if !mutex.value.check()? {
+            let guard = mutex.sem.access()?;
+            unsafe { mutex.value.init(value)?; }
+            drop(guard);
+        }
+        Ok(mutex)
+    }
+
+    /// Removes the mutex named `name` from the system.
+    ///
+    /// The type of the value doesn't matter here, but has to be named anyway,
+    /// for example `Mutex::<u64>::unlink("counter")`.
+    ///
+    /// Both the lock and the shared memory holding the value are removed,
+    /// even if removing one of them fails.
+    pub fn unlink(name: &str) -> Result<()> {
+        Mutex::<T>::unlink_with_options(&OpenOptions::new(), name)
+    }
+
+    /// Removes the mutex named `name` which was opened with the options
+    /// specified by `opts`.
+    ///
+    /// Only the naming options of `opts` are used.
+    pub fn unlink_with_options(opts: &OpenOptions, name: &str) -> Result<()> {
+        let mut opts = opts.clone();
+        opts.backend(Backend::SystemV);
+        let sem = opts.unlink(name);
+        sem.and(Value::<T>::unlink(&opts, name, "mutex"))
+    }
+
+    /// Removes this mutex from the system.
+    ///
+    /// Other processes which have the mutex open get an error the next time
+    /// they try to lock it.
+    pub fn remove(self) -> Result<()> {
+        let shm = self.value.remove();
+        self.sem.remove().and(shm)
+    }
+
+    /// Acquires this mutex, blocking until it is available.
+    ///
+    /// # Errors
+    ///
+    /// If the previous owner of the mutex exited while holding it the mutex
+    /// is still acquired, but `LockError::OwnerDied` is returned with the
+    /// guard. Otherwise the errors are those described in
+    /// `Semaphore::acquire`, as `LockError::Io`.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.guard(self.sem.access()?)
+    }
+
+    /// Attempts to acquire this mutex without blocking, returning `None` if it
+    /// is already locked.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        self.sem.try_access()?.map(|g| self.guard(g)).transpose()
+    }
+
+    /// Attempts to acquire this mutex, blocking for at most `dur`.
+    pub fn lock_timeout(&self, dur: Duration) -> TryLockResult<MutexGuard<'_, T>> {
+        self.sem.access_timeout(dur)?.map(|g| self.guard(g)).transpose()
+    }
+
+    /// Attempts to acquire this mutex, blocking until `deadline` at the
+    /// latest.
+    pub fn lock_until(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T>> {
+        self.sem.access_until(deadline)?.map(|g| self.guard(g)).transpose()
+    }
+
+    /// Take ownership of the value once `guard` has been acquired, checking
+    /// whether the previous owner unlocked it.
+    fn guard<'a>(&'a self, guard: Guard<'a>) -> LockResult<MutexGuard<'a, T>> {
+        let died = self.value.acquired();
+        let guard = MutexGuard { mutex: self, _guard: guard };
+        if died {
+            return Err(LockError::OwnerDied(guard))
+        }
+        Ok(guard)
+    }
+}
+
+/// A value of type `T` in a named shared memory region, for the locks which
+/// protect one.
+///
+/// The value is preceded by a `Header`, and is only initialized by `init`.
+/// Accessing the value is up to the lock using this.
+pub struct Value<T: Pod> {
+    shm: Shm,
+    marker: PhantomData<T>,
+}
+
+impl<T: Pod> Value<T> {
+    /// Opens the region holding the value of the `kind` of lock named `name`.
+    pub fn open(opts: &OpenOptions, name: &str, kind: &str) -> Result<Value<T>> {
+        let (shm, _) = unsafe {
+            Shm::open(&shm::name(opts, &opts.name(name), kind, opts.create)?,
+                      Value::<T>::offset() + mem::size_of::<T>(),
+                      opts.create, opts.create_new, opts.mode)?
+        };
+        Ok(Value { shm, marker: PhantomData })
+    }
+
+    /// Removes the region holding the value of the `kind` of lock named
+    /// `name`.
+    pub fn unlink(opts: &OpenOptions, name: &str, kind: &str) -> Result<()> {
+        unsafe { Shm::unlink(&shm::name(opts, &opts.name(name), kind, false)?) }
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        unsafe { self.shm.remove() }
+    }
+
+    /// Returns whether the value has been initialized, checking that it is a
+    /// `T` if so.
+    ///
+    /// The header is filled in before the value is marked as initialized, so
+    /// this doesn't need the lock. Opening a lock which is held by somebody
+    /// else thus only blocks if it is brand new.
+    pub fn check(&self) -> Result<bool> {
+        let header = self.header();
+        if header.state.load(SeqCst) == UNINIT {
+            return Ok(false)
+        }
+        if header.size.load(SeqCst) != mem::size_of::<T>() as u32 ||
+           header.align.load(SeqCst) != mem::align_of::<T>() as u32 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "lock holds a value of a different type"))
+        }
+        Ok(true)
+    }
+
+    /// Stores `value` if the value hasn't been initialized yet, or checks
+    /// that the stored value is a `T` otherwise.
+    ///
+    /// The caller must hold the lock exclusively.
+    pub unsafe fn init(&self, value: T) -> Result<()> {
+        if self.check()? {
+            return Ok(())
+        }
+        let header = self.header();
+        *self.get() = value;
+        header.size.store(mem::size_of::<T>() as u32, SeqCst);
+        header.align.store(mem::align_of::<T>() as u32, SeqCst);
+        header.state.store(READY, SeqCst);
+        Ok(())
+    }
+
+    /// Records the current process as the exclusive owner, returning whether
+    /// the previous owner died without calling `released`.
+    pub fn acquired(&self) -> bool {
+        self.header().owner.swap(process::id(), SeqCst) != 0
+    }
+
+    /// Records that the exclusive owner unlocked normally.
+    ///
+    /// This must happen before the lock is actually released, so that nobody
+    /// can take the lock and mistake our pid for a dead owner.
+    pub fn released(&self) {
+        self.header().owner.store(0, SeqCst);
+    }
+
+    /// Returns whether the last exclusive owner died, without taking over.
+    pub fn owner_died(&self) -> bool {
+        self.header().owner.load(SeqCst) != 0
+    }
+
+    /// Returns a pointer to the value.
+    pub fn get(&self) -> *mut T {
+        unsafe { self.shm.as_ptr().add(Value::<T>::offset()) as *mut T }
+    }
+
+    /// The offset of the value from the start of the region, which is page
+    /// aligned.
+    fn offset() -> usize {
+        mem::size_of::<Header>().next_multiple_of(mem::align_of::<T>())
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*(self.shm.as_ptr() as *const Header) }
+    }
+}
+
+impl<'a, T: Pod> MutexGuard<'a, T> {
+    /// Returns the mutex `guard` locks, for unlocking and relocking it while
+    /// waiting on a `Condvar`.
+    ///
+    /// This is an associated function so that it can't shadow methods of
+    /// `T`.
+    #[cfg(all(target_os = "linux",
+              any(target_arch = "x86", target_arch = "x86_64",
+                  target_arch = "arm", target_arch = "aarch64")))]
+    pub(crate) fn mutex(guard: &MutexGuard<'a, T>) -> &'a Mutex<T> {
+        guard.mutex
+    }
+}
+
+impl<'a, T: Pod> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: Pod> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T: Pod> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Runs before the semaphore guard is dropped.
+        self.mutex.value.released();
+    }
+}
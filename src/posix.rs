@@ -0,0 +1,235 @@
+//! Bindings to POSIX named semaphores
+//!
+//! These are the semaphores created by `sem_open`, which live in `/dev/shm` on
+//! linux. Unlike the System V semaphores in `unix.rs` they are named by a
+//! plain string which is passed through to the system untouched, so they can
+//! be shared with programs written in other languages.
+//!
+//! The price for this is that POSIX semaphores are much more limited: they
+//! can only be adjusted by one at a time and operations on them are never
+//! undone when a process exits.
+
+#![allow(bad_style)]
+
+use std::ffi::CString;
+use std::io::{Result, Error, ErrorKind};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use libc;
+
+use {error, OpenOptions, SemaphoreStats};
+
+pub struct Semaphore { sem: *mut sem_t, name: CString }
+
+unsafe impl Send for Semaphore {}
+unsafe impl Sync for Semaphore {}
+
+pub enum sem_t {}
+
+#[cfg(target_os = "linux")]
+const SEM_FAILED: *mut sem_t = ::std::ptr::null_mut();
+#[cfg(not(target_os = "linux"))]
+const SEM_FAILED: *mut sem_t = !0 as *mut sem_t;
+
+extern "C" {
+    fn sem_open(name: *const libc::c_char, oflag: libc::c_int, ...) -> *mut sem_t;
+    fn sem_close(sem: *mut sem_t) -> libc::c_int;
+    fn sem_unlink(name: *const libc::c_char) -> libc::c_int;
+    fn sem_wait(sem: *mut sem_t) -> libc::c_int;
+    fn sem_trywait(sem: *mut sem_t) -> libc::c_int;
+    fn sem_post(sem: *mut sem_t) -> libc::c_int;
+    #[cfg(target_os = "linux")]
+    fn sem_timedwait(sem: *mut sem_t,
+                     abs_timeout: *const libc::timespec) -> libc::c_int;
+    #[cfg(target_os = "linux")]
+    fn sem_getvalue(sem: *mut sem_t, sval: *mut libc::c_int) -> libc::c_int;
+}
+
+/// Emulation of `sem_timedwait` for platforms which don't have it, polling
+/// with `sem_trywait` until the timeout passes.
+#[cfg(not(target_os = "linux"))]
+unsafe fn sem_timedwait(sem: *mut sem_t,
+                        abs_timeout: *const libc::timespec) -> libc::c_int {
+    use std::thread;
+    use std::time::Duration;
+
+    let deadline = UNIX_EPOCH + Duration::new((*abs_timeout).tv_sec as u64,
+                                              (*abs_timeout).tv_nsec as u32);
+    loop {
+        if sem_trywait(sem) == 0 { return 0 }
+        if Error::last_os_error().raw_os_error() != Some(libc::EAGAIN) {
+            return -1
+        }
+        match deadline.duration_since(SystemTime::now()) {
+            Ok(dur) => thread::sleep(::std::cmp::min(dur, Duration::from_millis(1))),
+            Err(..) => {
+                set_errno(libc::ETIMEDOUT);
+                return -1
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn set_errno(errno: libc::c_int) {
+    extern "C" { fn __error() -> *mut libc::c_int; }
+    *__error() = errno;
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn sem_getvalue(_sem: *mut sem_t, _sval: *mut libc::c_int) -> libc::c_int {
+    set_errno(libc::ENOSYS);
+    -1
+}
+
+impl Semaphore {
+    pub unsafe fn new(name: &str, opts: &OpenOptions) -> Result<Semaphore> {
+        let name = cstring(name)?;
+        let mut flags = 0;
+        if opts.create_new {
+            flags |= libc::O_CREAT | libc::O_EXCL;
+        } else if opts.create {
+            flags |= libc::O_CREAT;
+        }
+        if opts.initial > libc::c_int::MAX as usize {
+            return Err(error::Error::io(error::ErrorKind::Overflow, None))
+        }
+        let sem = sem_open(name.as_ptr(), flags,
+                           (opts.mode & 0o777) as libc::c_uint,
+                           opts.initial as libc::c_uint);
+        if sem == SEM_FAILED {
+            return Err(last_error())
+        }
+        Ok(Semaphore { sem, name })
+    }
+
+    pub unsafe fn remove(self) -> Result<()> {
+        if sem_unlink(self.name.as_ptr()) != 0 {
+            return Err(last_error())
+        }
+        Ok(())
+    }
+
+    pub unsafe fn unlink(name: &str, _opts: &OpenOptions) -> Result<()> {
+        let name = cstring(name)?;
+        if sem_unlink(name.as_ptr()) != 0 {
+            return Err(last_error())
+        }
+        Ok(())
+    }
+
+    pub unsafe fn wait(&self, n: usize, deadline: Option<Instant>,
+                       _undo: bool) -> Result<bool> {
+        // POSIX semaphores can only be decremented one at a time, so take the
+        // resources one by one and hand back what we got if we time out.
+        for i in 0..n {
+            match self.wait_one(deadline) {
+                Ok(true) => {}
+                res => { let _ = self.post(i, false); return res }
+            }
+        }
+        Ok(true)
+    }
+
+    unsafe fn wait_one(&self, deadline: Option<Instant>) -> Result<bool> {
+        loop {
+            let rc = match deadline {
+                // sem_timedwait takes an absolute time on the system clock, so
+                // translate our deadline to that each time we're interrupted.
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let abs = SystemTime::now().duration_since(UNIX_EPOCH)
+                                               .unwrap() + remaining;
+                    let timeout = libc::timespec {
+                        tv_sec: abs.as_secs() as libc::time_t,
+                        tv_nsec: abs.subsec_nanos() as libc::c_long,
+                    };
+                    sem_timedwait(self.sem, &timeout)
+                }
+                None => sem_wait(self.sem),
+            };
+            if rc == 0 { return Ok(true) }
+
+            match last_error() {
+                ref e if e.raw_os_error() == Some(libc::EINTR) => {}
+                ref e if e.raw_os_error() == Some(libc::ETIMEDOUT) => return Ok(false),
+                e => return Err(e)
+            }
+        }
+    }
+
+    pub unsafe fn try_wait(&self, n: usize, _undo: bool) -> Result<bool> {
+        for i in 0..n {
+            if sem_trywait(self.sem) != 0 {
+                let err = last_error();
+                let _ = self.post(i, false);
+                return match err.raw_os_error() {
+                    Some(libc::EAGAIN) => Ok(false),
+                    _ => Err(err),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    pub unsafe fn wait_zero(&self, _deadline: Option<Instant>) -> Result<bool> {
+        Err(Error::other("POSIX semaphores do not support waiting for zero"))
+    }
+
+    pub unsafe fn try_wait_zero(&self) -> Result<bool> {
+        Err(Error::other("POSIX semaphores do not support waiting for zero"))
+    }
+
+    pub unsafe fn post(&self, n: usize, _undo: bool) -> Result<()> {
+        for _ in 0..n {
+            if sem_post(self.sem) != 0 {
+                return Err(last_error())
+            }
+        }
+        Ok(())
+    }
+
+    pub unsafe fn set_permissions(&self, _mode: u32) -> Result<()> {
+        Err(Error::other("POSIX semaphores do not support changing permissions"))
+    }
+
+    pub unsafe fn set_owner(&self, _uid: u32, _gid: u32) -> Result<()> {
+        Err(Error::other("POSIX semaphores do not support changing ownership"))
+    }
+
+    pub unsafe fn stats(&self) -> Result<SemaphoreStats> {
+        Err(Error::other("POSIX semaphores only support querying the value"))
+    }
+
+    pub unsafe fn value(&self) -> Result<usize> {
+        let mut value = 0;
+        if sem_getvalue(self.sem, &mut value) != 0 {
+            return Err(last_error())
+        }
+        // Some systems report waiters as a negative value.
+        Ok(if value < 0 {0} else {value as usize})
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        unsafe { sem_close(self.sem); }
+    }
+}
+
+fn cstring(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "semaphore name contains a nul byte")
+    })
+}
+
+/// Returns the last OS error, wrapping the errors which callers may want to
+/// recover from in an `error::Error`.
+fn last_error() -> Error {
+    let err = Error::last_os_error();
+    let kind = match err.raw_os_error() {
+        Some(libc::EACCES) => error::ErrorKind::PermissionDenied,
+        Some(libc::EOVERFLOW) => error::ErrorKind::Overflow,
+        _ => return err,
+    };
+    error::Error::io(kind, err.raw_os_error())
+}
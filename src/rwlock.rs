@@ -0,0 +1,304 @@
+//! A reader-writer lock protecting data in shared memory
+//!
+//! The value is stored like the value of a `Mutex`, but the lock is a
+//! semaphore set counting the readers and writers holding it. Every state
+//! change is a single `semop` which both checks and updates the counts, so
+//! there is no window in which a reader and a writer can both get in.
+
+use std::io::Result;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use mutex::{Pod, Value};
+use {LockError, LockResult, Op, OpenOptions, SemaphoreSet};
+use {Semantics, TryLockResult};
+
+/// The number of processes holding the lock for reading.
+const READERS: usize = 0;
+/// The number of processes holding the lock for writing, at most 1.
+const WRITER: usize = 1;
+/// The number of writers waiting for the lock, which readers preferring
+/// writers wait on.
+const WAITING: usize = 2;
+
+/// A reader-writer lock protecting a value shared between processes.
+///
+/// Any number of processes may hold the lock for reading at the same time,
+/// but only one may hold it for writing, with no readers. Like `Mutex` the
+/// value lives in shared memory, the lock is released if a process exits
+/// while holding it, and a writer exiting while holding the lock is reported
+/// to the next reader or writer through `LockError::OwnerDied`. Readers can't
+/// repair the value, so they keep being told until a writer has unlocked the
+/// lock normally.
+///
+/// By default readers are preferred, see `RwLock::with_options`.
+///
+/// # Example
+///
+/// ```
+/// use ipc::RwLock;
+///
+/// let lock = RwLock::new("rwlock-example", [0u8; 16]).unwrap();
+///
+/// lock.write().unwrap()[0] = 1;
+/// {
+///     let r1 = lock.read().unwrap();
+///     let r2 = lock.read().unwrap();
+///     assert_eq!(r1[0], r2[0]);
+///     assert!(lock.try_write().unwrap().is_none());
+/// }
+/// lock.remove().unwrap();
+/// ```
+pub struct RwLock<T: Pod> {
+    set: SemaphoreSet,
+    value: Value<T>,
+    prefer_writers: bool,
+}
+
+/// An RAII guard giving shared access to the value of an `RwLock`.
+pub struct RwLockReadGuard<'a, T: Pod + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+/// An RAII guard giving exclusive access to the value of an `RwLock`.
+pub struct RwLockWriteGuard<'a, T: Pod + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: Pod> RwLock<T> {
+    /// Opens the lock named `name`, creating it with the value `value` if it
+    /// doesn't already exist.
+    ///
+    /// This is equivalent to `RwLock::with_options(&Semaphore::options(),
+    /// name, value, false)`.
+    pub fn new(name: &str, value: T) -> Result<RwLock<T>> {
+        RwLock::with_options(&OpenOptions::new(), name, value, false)
+    }
+
+    /// Opens the lock named `name` with the options specified by `opts`.
+    ///
+    /// This behaves like `Mutex::with_options`, but is backed by a
+    /// `SemaphoreSet` with the same name rather than by a `Semaphore`.
+    ///
+    /// `prefer_writers` sets whether waiting writers go before new readers.
+    /// Otherwise readers may keep acquiring a lock which is already held by
+    /// other readers, which can starve writers for as long as readers keep
+    /// overlapping. Preferring writers makes new readers wait for any waiting
+    /// writers instead, which can starve readers in turn. This only affects
+    /// the returned handle, so every process should agree on it.
+    pub fn with_options(opts: &OpenOptions, name: &str, value: T,
+                        prefer_writers: bool) -> Result<RwLock<T>> {
+        let mut opts = opts.clone();
+        opts.semantics(Semantics::Lock);
+        let lock = RwLock {
+            set: opts.open_set(name, &[0, 0, 0])?,
+            value: Value::open(&opts, name, "rwlock")?,
+            prefer_writers,
+        };
+
+        if lock.value.check()? {
+            return Ok(lock)
+        }
+        lock.set.apply(&RwLock::<T>::write_ops(false))?;
+        let res = unsafe { lock.value.init(value) };
+        lock.set.apply(&[Op::acquire(WRITER, 1)])?;
+        res.map(|()| lock)
+    }
+
+    /// Removes the lock named `name` from the system.
+    ///
+    /// This behaves like `Mutex::unlink`.
+    pub fn unlink(name: &str) -> Result<()> {
+        RwLock::<T>::unlink_with_options(&OpenOptions::new(), name)
+    }
+
+    /// Removes the lock named `name` which was opened with the options
+    /// specified by `opts`.
+    ///
+    /// This behaves like `Mutex::unlink_with_options`.
+    pub fn unlink_with_options(opts: &OpenOptions, name: &str) -> Result<()> {
+        let set = opts.unlink(name);
+        set.and(Value::<T>::unlink(opts, name, "rwlock"))
+    }
+
+    /// Removes this lock from the system.
+    ///
+    /// Other processes which have the lock open get an error the next time
+    /// they try to lock it.
+    pub fn remove(self) -> Result<()> {
+        let shm = self.value.remove();
+        self.set.remove().and(shm)
+    }
+
+    /// Locks this lock for reading, blocking until no writer holds it.
+    ///
+    /// If this handle prefers writers this also waits until no writer is
+    /// waiting for the lock.
+    ///
+    /// # Errors
+    ///
+    /// The errors are those described in `Mutex::lock`.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.set.apply(&self.read_ops())?;
+        self.read_guard()
+    }
+
+    /// Attempts to lock this lock for reading without blocking, returning
+    /// `None` if that's not possible.
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        if !self.set.try_apply(&self.read_ops())? {
+            return Ok(None)
+        }
+        self.read_guard().map(Some)
+    }
+
+    /// Attempts to lock this lock for reading, blocking for at most `dur`.
+    pub fn read_timeout(&self, dur: Duration)
+                        -> TryLockResult<RwLockReadGuard<'_, T>> {
+        if !self.set.apply_timeout(&self.read_ops(), dur)? {
+            return Ok(None)
+        }
+        self.read_guard().map(Some)
+    }
+
+    /// Attempts to lock this lock for reading, blocking until `deadline` at
+    /// the latest.
+    pub fn read_until(&self, deadline: Instant)
+                      -> TryLockResult<RwLockReadGuard<'_, T>> {
+        if !self.set.apply_until(&self.read_ops(), deadline)? {
+            return Ok(None)
+        }
+        self.read_guard().map(Some)
+    }
+
+    /// Locks this lock for writing, blocking until nobody else holds it.
+    ///
+    /// # Errors
+    ///
+    /// The errors are those described in `Mutex::lock`.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.lock_write(None)?;
+        self.write_guard()
+    }
+
+    /// Attempts to lock this lock for writing without blocking, returning
+    /// `None` if that's not possible.
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        if !self.set.try_apply(&RwLock::<T>::write_ops(false))? {
+            return Ok(None)
+        }
+        self.write_guard().map(Some)
+    }
+
+    /// Attempts to lock this lock for writing, blocking for at most `dur`.
+    pub fn write_timeout(&self, dur: Duration)
+                         -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        match Instant::now().checked_add(dur) {
+            Some(deadline) => self.write_until(deadline),
+            None => self.write().map(Some),
+        }
+    }
+
+    /// Attempts to lock this lock for writing, blocking until `deadline` at
+    /// the latest.
+    pub fn write_until(&self, deadline: Instant)
+                       -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        if !self.lock_write(Some(deadline))? {
+            return Ok(None)
+        }
+        self.write_guard().map(Some)
+    }
+
+    /// Wait for the write lock, announcing ourselves as a waiting writer in
+    /// the meantime if writers are preferred.
+    fn lock_write(&self, deadline: Option<Instant>) -> Result<bool> {
+        let apply = |ops: &[Op]| match deadline {
+            Some(deadline) => self.set.apply_until(ops, deadline),
+            None => self.set.apply(ops).map(|()| true),
+        };
+        if !self.prefer_writers {
+            return apply(&RwLock::<T>::write_ops(false))
+        }
+        self.set.apply(&[Op::release(WAITING, 1)])?;
+        match apply(&RwLock::<T>::write_ops(true)) {
+            Ok(true) => Ok(true),
+            res => {
+                let _ = self.set.apply(&[Op::acquire(WAITING, 1)]);
+                res
+            }
+        }
+    }
+
+    /// The operations taking a read lock.
+    fn read_ops(&self) -> Vec<Op> {
+        let mut ops = vec![Op::wait_zero(WRITER)];
+        if self.prefer_writers {
+            ops.push(Op::wait_zero(WAITING));
+        }
+        ops.push(Op::release(READERS, 1));
+        ops
+    }
+
+    /// The operations taking a write lock, taking back the announcement of
+    /// a waiting writer if `waiting`.
+    fn write_ops(waiting: bool) -> Vec<Op> {
+        let mut ops = vec![Op::wait_zero(WRITER), Op::wait_zero(READERS),
+                           Op::release(WRITER, 1)];
+        if waiting {
+            ops.push(Op::acquire(WAITING, 1));
+        }
+        ops
+    }
+
+    fn read_guard(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let guard = RwLockReadGuard { lock: self };
+        if self.value.owner_died() {
+            return Err(LockError::OwnerDied(guard))
+        }
+        Ok(guard)
+    }
+
+    fn write_guard(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let died = self.value.acquired();
+        let guard = RwLockWriteGuard { lock: self };
+        if died {
+            return Err(LockError::OwnerDied(guard))
+        }
+        Ok(guard)
+    }
+}
+
+impl<'a, T: Pod> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: Pod> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        let _ = self.lock.set.apply(&[Op::acquire(READERS, 1)]);
+    }
+}
+
+impl<'a, T: Pod> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T: Pod> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T: Pod> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.value.released();
+        let _ = self.lock.set.apply(&[Op::acquire(WRITER, 1)]);
+    }
+}
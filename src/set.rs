@@ -0,0 +1,246 @@
+//! Sets of semaphores operated on atomically
+//!
+//! System V semaphores are natively sets, and `semop` applies any number of
+//! operations on members of one set in a single atomic step. This lets a
+//! process acquire several resources at once without having to worry about
+//! the order in which other processes acquire them.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+use libc;
+
+use imp::{self, consts::{sembuf, SEM_UNDO}};
+use {error, Backend, OpenOptions, Semantics, SemaphoreStats};
+
+/// A set of counters which can be shared across processes, and whose members
+/// can be acquired and released together atomically.
+///
+/// Each member behaves like a `Semaphore`, but `apply` operates on any
+/// number of members at once: either all of the operations happen, or none of
+/// them do and the call blocks until all of them can happen. This is only
+/// available on unix, where it is backed by a single System V semaphore set.
+///
+/// # Example
+///
+/// ```
+/// use ipc::{Op, SemaphoreSet};
+///
+/// // two resources which are available once each
+/// let set = SemaphoreSet::new("set-example", &[1, 1]).unwrap();
+///
+/// // take both at once, regardless of who else is taking them in which order
+/// set.apply(&[Op::acquire(0, 1), Op::acquire(1, 1)]).unwrap();
+/// set.apply(&[Op::release(0, 1), Op::release(1, 1)]).unwrap();
+///
+/// set.remove().unwrap();
+/// ```
+pub struct SemaphoreSet {
+    inner: imp::Set,
+    semantics: Semantics,
+}
+
+/// An operation on one member of a `SemaphoreSet`, as passed to
+/// `SemaphoreSet::apply`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Op {
+    index: usize,
+    kind: Kind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Take the amount from the count.
+    Acquire(usize),
+    /// Add the amount to the count.
+    Release(usize),
+    WaitZero,
+}
+
+impl SemaphoreSet {
+    /// Creates a new set with the given name and one member for each count in
+    /// `counts`.
+    ///
+    /// If the current system already has a set named `name` then a handle to
+    /// that set is returned, and `counts` is only used to check that it has
+    /// as many members. This is equivalent to
+    /// `Semaphore::options().open_set(name, counts)`.
+    ///
+    /// Sets share their names with semaphores, so a set can't have the same
+    /// name as a semaphore.
+    pub fn new(name: &str, counts: &[usize]) -> Result<SemaphoreSet> {
+        OpenOptions::new().open_set(name, counts)
+    }
+
+    /// Removes this set from the system.
+    ///
+    /// This behaves like `Semaphore::remove`. To remove a set without opening
+    /// it first use `Semaphore::unlink`.
+    pub fn remove(self) -> Result<()> {
+        unsafe { self.inner.remove() }
+    }
+
+    /// Returns the number of members in this set.
+    pub fn len(&self) -> usize { self.inner.len() }
+
+    /// Returns whether this set has no members.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Applies all of `ops` at once.
+    ///
+    /// This function will block until every operation can be applied, and
+    /// then apply all of them in one atomic step. Operations on the same
+    /// member are applied in order. Applying no operations returns
+    /// immediately.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `Semaphore::acquire`, an error of
+    /// kind `InvalidInput` is returned if an operation refers to a member
+    /// which doesn't exist, and one of kind `ipc::ErrorKind::Overflow` if the
+    /// amount of an operation is larger than `i16::MAX`.
+    pub fn apply(&self, ops: &[Op]) -> Result<()> {
+        let mut ops = self.ops(ops)?;
+        unsafe { self.inner.apply(&mut ops, true, None).map(|_| ()) }
+    }
+
+    /// Attempts to apply all of `ops` at once.
+    ///
+    /// This function is identical to `apply` except that it will never block.
+    /// Either all of `ops` are applied and `true` is returned, or none of them
+    /// are and `false` is returned.
+    pub fn try_apply(&self, ops: &[Op]) -> Result<bool> {
+        let mut ops = self.ops(ops)?;
+        unsafe { self.inner.apply(&mut ops, false, None) }
+    }
+
+    /// Attempts to apply all of `ops` at once, blocking for at most `dur`.
+    ///
+    /// This function is identical to `apply` except that it will give up once
+    /// `dur` has elapsed, returning `false`.
+    pub fn apply_timeout(&self, ops: &[Op], dur: Duration) -> Result<bool> {
+        match Instant::now().checked_add(dur) {
+            Some(deadline) => self.apply_until(ops, deadline),
+            None => self.apply(ops).map(|()| true),
+        }
+    }
+
+    /// Attempts to apply all of `ops` at once, blocking until `deadline` at
+    /// the latest.
+    ///
+    /// This function is identical to `apply_timeout` except that the limit is
+    /// expressed as an absolute point in time.
+    pub fn apply_until(&self, ops: &[Op], deadline: Instant) -> Result<bool> {
+        let mut ops = self.ops(ops)?;
+        unsafe { self.inner.apply(&mut ops, true, Some(deadline)) }
+    }
+
+    /// Returns the current count of the member `index`.
+    pub fn value(&self, index: usize) -> Result<usize> {
+        self.check(index)?;
+        unsafe { self.inner.get(index, imp::consts::GETVAL).map(|v| v as usize) }
+    }
+
+    /// Returns a snapshot of the current state of the member `index`.
+    pub fn stats(&self, index: usize) -> Result<SemaphoreStats> {
+        self.check(index)?;
+        unsafe { self.inner.stats(index) }
+    }
+
+    /// Changes the permission bits of this set, see
+    /// `Semaphore::set_permissions`.
+    pub fn set_permissions(&self, mode: u32) -> Result<()> {
+        unsafe { self.inner.set_permissions(mode) }
+    }
+
+    /// Changes the user and group owning this set, see `Semaphore::set_owner`.
+    pub fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        unsafe { self.inner.set_owner(uid, gid) }
+    }
+
+    /// Returns the semantics used by `apply` on this handle.
+    pub fn semantics(&self) -> Semantics { self.semantics }
+
+    /// Translates `ops` into what `semop` expects.
+    fn ops(&self, ops: &[Op]) -> Result<Vec<sembuf>> {
+        let flags = match self.semantics {
+            Semantics::Lock => SEM_UNDO,
+            Semantics::Signal => 0,
+        };
+        let mut bufs = Vec::with_capacity(ops.len());
+        for op in ops {
+            self.check(op.index)?;
+            let (amt, flags) = match op.kind {
+                // a `sem_op` of zero means something else entirely
+                Kind::Acquire(0) | Kind::Release(0) => continue,
+                Kind::Acquire(n) | Kind::Release(n)
+                    if n > i16::MAX as usize => {
+                    return Err(error::Error::io(error::ErrorKind::Overflow, None))
+                }
+                Kind::Acquire(n) => (-(n as libc::c_short), flags),
+                Kind::Release(n) => (n as libc::c_short, flags),
+                Kind::WaitZero => (0, 0),
+            };
+            bufs.push(sembuf {
+                sem_num: op.index as libc::c_ushort,
+                sem_op: amt,
+                sem_flg: flags,
+            });
+        }
+        Ok(bufs)
+    }
+
+    fn check(&self, index: usize) -> Result<()> {
+        if index >= self.len() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("set has no member {}", index)))
+        }
+        Ok(())
+    }
+}
+
+impl Op {
+    /// Acquires `n` resources of the member `index`, blocking while fewer are
+    /// available.
+    pub fn acquire(index: usize, n: usize) -> Op {
+        Op { index, kind: Kind::Acquire(n) }
+    }
+
+    /// Releases `n` resources of the member `index`.
+    pub fn release(index: usize, n: usize) -> Op {
+        Op { index, kind: Kind::Release(n) }
+    }
+
+    /// Blocks until the count of the member `index` is zero, without changing
+    /// it.
+    ///
+    /// Combined with other operations this allows for example releasing one
+    /// member only once another one has drained.
+    pub fn wait_zero(index: usize) -> Op {
+        Op { index, kind: Kind::WaitZero }
+    }
+
+    /// Returns the index of the member this operation applies to.
+    pub fn index(&self) -> usize { self.index }
+}
+
+impl OpenOptions {
+    /// Opens the semaphore set named `name` with the options specified by
+    /// `self`, with one member for each count in `counts`.
+    ///
+    /// The counts are the initial counts of the members if the set is
+    /// created, and `initial` is ignored. The semantics apply to every
+    /// operation performed through `SemaphoreSet::apply`.
+    ///
+    /// # Errors
+    ///
+    /// Along with the errors described in `open`, an error is returned if the
+    /// backend isn't `Backend::SystemV`, and one of kind `InvalidInput` if an
+    /// existing set doesn't have exactly as many members as `counts`.
+    pub fn open_set(&self, name: &str, counts: &[usize]) -> Result<SemaphoreSet> {
+        if self.backend != Backend::SystemV {
+            return Err(Error::other("only System V semaphores can be sets"))
+        }
+        let inner = unsafe { imp::Set::new(&self.name(name), self, counts)? };
+        Ok(SemaphoreSet { inner, semantics: self.semantics })
+    }
+}
@@ -0,0 +1,158 @@
+//! Named shared memory regions
+//!
+//! A thin wrapper around `shm_open` and `mmap` for the primitives in this
+//! crate which keep their state in memory shared between processes rather
+//! than in a kernel object.
+//!
+//! A region is zero-filled when it is first created, so the primitives built
+//! on top of it use an all-zero state to mean "not yet initialized".
+
+use std::ffi::CString;
+use std::io::{Result, Error, ErrorKind};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+use libc;
+
+use {hash, imp, OpenOptions};
+
+pub struct Shm {
+    ptr: *mut libc::c_void,
+    len: usize,
+    fd: libc::c_int,
+    name: CString,
+}
+
+unsafe impl Send for Shm {}
+unsafe impl Sync for Shm {}
+
+// Only the futex backend changes the permissions of its region.
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+extern "C" {
+    fn fchmod(fd: libc::c_int, mode: libc::mode_t) -> libc::c_int;
+    fn fchown(fd: libc::c_int, owner: libc::uid_t,
+              group: libc::gid_t) -> libc::c_int;
+}
+
+/// Returns the name of the shared memory object backing the `kind` of
+/// primitive named `name`, which is already namespaced.
+///
+/// Shared memory objects live in a single flat namespace, so the name includes
+/// a hash of the key directory as well, which is created first if `create`.
+/// Primitives opened with different `dir` or `per_user` options thus never
+/// share their memory, just like semaphores.
+pub fn name(opts: &OpenOptions, name: &str, kind: &str,
+            create: bool) -> Result<String> {
+    let dir = unsafe { imp::key_dir(opts, create)? };
+    Ok(format!("/ipc-rs-{}-{}-{:016x}", kind, imp::mangle(name),
+               hash::fnv1a(dir.as_os_str().as_bytes())))
+}
+
+impl Shm {
+    /// Opens the shared memory object `name` and maps `len` bytes of it.
+    ///
+    /// Returns the region along with whether it was created by this call, in
+    /// which case the caller is responsible for initializing it.
+    pub unsafe fn open(name: &str, len: usize, create: bool, create_new: bool,
+                       mode: u32) -> Result<(Shm, bool)> {
+        let name = CString::new(name).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "name contains a nul byte")
+        })?;
+        let mut created = false;
+        let mut fd = -1;
+        if create || create_new {
+            fd = libc::shm_open(name.as_ptr(),
+                                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                                (mode & 0o777) as libc::mode_t);
+            if fd >= 0 {
+                created = true;
+            } else {
+                match Error::last_os_error() {
+                    ref e if e.raw_os_error() == Some(libc::EEXIST) &&
+                             !create_new => {}
+                    e => return Err(e)
+                }
+            }
+        }
+        if fd < 0 {
+            fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0);
+            if fd < 0 { return Err(Error::last_os_error()) }
+        }
+
+        // The creator may not have gotten around to sizing the object yet, so
+        // whoever sees it too small extends it. Everyone agrees on the size so
+        // this is harmless, and the new bytes are zero either way.
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut stat) != 0 ||
+           ((stat.st_size as usize) < len &&
+            libc::ftruncate(fd, len as libc::off_t) != 0) {
+            let err = Error::last_os_error();
+            libc::close(fd);
+            return Err(err)
+        }
+
+        let ptr = libc::mmap(ptr::null_mut(), len as libc::size_t,
+                             libc::PROT_READ | libc::PROT_WRITE,
+                             libc::MAP_SHARED, fd, 0);
+        if ptr == libc::MAP_FAILED {
+            let err = Error::last_os_error();
+            libc::close(fd);
+            return Err(err)
+        }
+        Ok((Shm { ptr, len, fd, name }, created))
+    }
+
+    /// Removes the shared memory object `name` from the system.
+    ///
+    /// Existing mappings of it remain valid.
+    pub unsafe fn unlink(name: &str) -> Result<()> {
+        let name = CString::new(name).map_err(|_| {
+            Error::new(ErrorKind::InvalidInput, "name contains a nul byte")
+        })?;
+        if libc::shm_unlink(name.as_ptr()) != 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Removes the shared memory object this region was mapped from.
+    pub unsafe fn remove(&self) -> Result<()> {
+        if libc::shm_unlink(self.name.as_ptr()) != 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    /// Returns a pointer to the start of the mapping, which is page aligned.
+    pub fn as_ptr(&self) -> *mut u8 { self.ptr as *mut u8 }
+}
+
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+impl Shm {
+    pub unsafe fn set_permissions(&self, mode: u32) -> Result<()> {
+        if fchmod(self.fd, (mode & 0o777) as libc::mode_t) != 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(())
+    }
+
+    pub unsafe fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        if fchown(self.fd, uid as libc::uid_t, gid as libc::gid_t) != 0 {
+            return Err(Error::last_os_error())
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Shm {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len as libc::size_t);
+            libc::close(self.fd);
+        }
+    }
+}
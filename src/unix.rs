@@ -19,32 +19,59 @@
 #![allow(bad_style)]
 
 use std::env;
+use std::ffi::CString;
 use std::fs;
-use std::hash::{Hash, Hasher, SipHasher};
 use std::io::{Result, Error, ErrorKind};
 use std::mem;
-use std::path::PathBuf;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use libc;
 use libc::consts::os::posix88::{EEXIST, O_RDWR};
 
 use self::consts::{IPC_CREAT, IPC_EXCL, key_t, sembuf, SEM_UNDO, IPC_NOWAIT};
-use self::consts::{IPC_STAT, IPC_RMID, SETVAL, semid_ds};
+use self::consts::{IPC_STAT, IPC_RMID, IPC_SET, IPC_PRIVATE, semid_ds};
+use self::consts::{GETPID, GETVAL, GETNCNT, GETZCNT, ipc_perm};
+use {error, hash, OpenOptions, SemaphoreStats};
 
-pub struct Semaphore { semid: libc::c_int }
+pub struct Semaphore { set: Set }
+
+/// A System V semaphore set, with `len` members for the user followed by
+/// `META` members for our own bookkeeping.
+pub struct Set { semid: libc::c_int, path: Option<PathBuf>, len: usize }
+
+/// The number of bookkeeping members at the end of every set, whose offsets
+/// from the last user member are below. `INIT` is raised once the user
+/// members have been initialized.
+///
+/// `ftok` only looks at some of the bits of the inode and device of the key
+/// file, so different key files can end up with the same key. To notice this
+/// the set also records a tag derived from the key file path, split into two
+/// 15-bit halves in `TAG_HI` and `TAG_LO` as that's all a member can hold.
+const META: usize = 3;
+const INIT: usize = 0;
+const TAG_HI: usize = 1;
+const TAG_LO: usize = 2;
 
 #[cfg(target_os = "linux")]
-mod consts {
+pub mod consts {
     use libc;
 
     pub type key_t = i32;
 
+    pub static IPC_PRIVATE: key_t = 0;
     pub static IPC_CREAT: libc::c_int = 0o1000;
     pub static IPC_EXCL: libc::c_int = 0o2000;
     pub static IPC_NOWAIT: libc::c_short = 0o4000;
     pub static SEM_UNDO: libc::c_short = 0x1000;
-    pub static SETVAL: libc::c_int = 16;
+    pub static GETPID: libc::c_int = 11;
+    pub static GETVAL: libc::c_int = 12;
+    pub static GETNCNT: libc::c_int = 14;
+    pub static GETZCNT: libc::c_int = 15;
     pub static IPC_STAT: libc::c_int = 2;
     pub static IPC_RMID: libc::c_int = 0;
+    pub static IPC_SET: libc::c_int = 1;
 
     #[repr(C)]
     pub struct sembuf {
@@ -83,18 +110,23 @@ mod consts {
 
 
 #[cfg(target_os = "macos")]
-mod consts {
+pub mod consts {
     use libc;
 
     pub type key_t = i32;
 
+    pub static IPC_PRIVATE: key_t = 0;
     pub static IPC_CREAT: libc::c_int = 0o1000;
     pub static IPC_EXCL: libc::c_int = 0o2000;
     pub static IPC_NOWAIT: libc::c_short = 0o4000;
     pub static SEM_UNDO: libc::c_short = 0o10000;
-    pub static SETVAL: libc::c_int = 8;
+    pub static GETNCNT: libc::c_int = 3;
+    pub static GETPID: libc::c_int = 4;
+    pub static GETVAL: libc::c_int = 5;
+    pub static GETZCNT: libc::c_int = 7;
     pub static IPC_STAT: libc::c_int = 2;
     pub static IPC_RMID: libc::c_int = 0;
+    pub static IPC_SET: libc::c_int = 1;
 
     #[repr(C)]
     pub struct sembuf {
@@ -133,200 +165,841 @@ mod consts {
     }
 }
 
-extern {
+extern "C" {
     fn ftok(pathname: *const libc::c_uchar, proj_id: libc::c_int) -> key_t;
     fn semget(key: key_t, nsems: libc::c_int, semflg: libc::c_int) -> libc::c_int;
     fn semctl(semid: libc::c_int, semnum: libc::c_int,
               cmd: libc::c_int, ...) -> libc::c_int;
     fn semop(semid: libc::c_int, sops: *mut sembuf,
              nsops: libc::c_uint) -> libc::c_int;
+    #[cfg(target_os = "linux")]
+    fn semtimedop(semid: libc::c_int, sops: *mut sembuf, nsops: libc::c_uint,
+                  timeout: *const libc::timespec) -> libc::c_int;
 }
 
-impl Semaphore {
-    pub unsafe fn new(name: &str, cnt: usize) -> Result<Semaphore> {
-        let key = try!(Semaphore::key(name));
+/// Emulation of `semtimedop` for platforms which don't have it.
+///
+/// The operations are retried with `IPC_NOWAIT` in a sleep loop until they
+/// either succeed or the timeout elapses, in which case `EAGAIN` is returned
+/// just like the real `semtimedop`.
+#[cfg(not(target_os = "linux"))]
+unsafe fn semtimedop(semid: libc::c_int, sops: *mut sembuf, nsops: libc::c_uint,
+                     timeout: *const libc::timespec) -> libc::c_int {
+    use std::slice;
+    use std::thread;
+
+    let deadline = Instant::now() + Duration::new((*timeout).tv_sec as u64,
+                                                  (*timeout).tv_nsec as u32);
+    for op in slice::from_raw_parts_mut(sops, nsops as usize) {
+        op.sem_flg |= IPC_NOWAIT;
+    }
+    loop {
+        let rc = semop(semid, sops, nsops);
+        if rc == 0 || Error::last_os_error().raw_os_error() != Some(libc::EAGAIN) {
+            return rc
+        }
+        let now = Instant::now();
+        if now >= deadline { return rc }
+        thread::sleep(::std::cmp::min(deadline - now, Duration::from_millis(1)));
+    }
+}
+
+impl Set {
+    /// Opens the set named `name` with one user member for each count in
+    /// `initial`, which are the initial counts if the set is created.
+    pub unsafe fn new(name: &str, opts: &OpenOptions,
+                      initial: &[usize]) -> Result<Set> {
+        let path = Set::filename(name, opts);
+        let create = opts.create || opts.create_new;
+        let key = Set::key(&path, opts.per_user,
+                           if create {Some(opts.mode)} else {None})?;
 
         // System V semaphores cannot be initialized at creation, and we don't
         // know which process is responsible for creating the semaphore, so we
         // partially assume that we are responsible.
         //
-        // In order to get "atomic create and initialization" we have a dirty
-        // hack here. First, an attempt is made to exclusively create the
-        // semaphore. If we succeed, then we're responsible for initializing it.
-        // If we fail, we need to wait for someone's initialization to succeed.
-        // We read off the `sem_otime` field in a loop to "wait until a
-        // semaphore is initialized." Sadly I don't know of a better way to get
-        // around this...
+        // To get "atomic create and initialization" the set has an extra
+        // member, `INIT`, which starts out at 0. First, an attempt is made to
+        // exclusively create the set. If we succeed, then we're responsible
+        // for initializing it, and we set the counts and raise `INIT` in one
+        // atomic operation. If we fail, we block until `INIT` is raised by
+        // whoever created the set.
         //
         // see http://beej.us/guide/bgipc/output/html/multipage/semaphores.html
-        let mut semid = semget(key, 1, IPC_CREAT | IPC_EXCL | 0o666);
+        let len = initial.len();
+        let nsems = (len + META) as libc::c_int;
+        let tag = Set::tag(&path)?;
+        let mut semid = -1;
+        if create {
+            let mode = (opts.mode & 0o777) as libc::c_int;
+            semid = semget(key, nsems, IPC_CREAT | IPC_EXCL | mode);
+            if semid < 0 {
+                match Error::last_os_error() {
+                    // Someone else created the semaphore, which is only a
+                    // problem if we were asked to create it ourselves.
+                    ref e if e.raw_os_error() == Some(EEXIST) &&
+                             !opts.create_new => {}
+                    e => return Err(e)
+                }
+            }
+        }
         if semid >= 0 {
-            let mut buf = sembuf {
-                sem_num: 0,
-                sem_op: cnt as libc::c_short,
-                sem_flg: 0
+            Set::init(semid, initial, tag)?;
+        } else {
+            // Re-attempt to get the semaphore, this should in theory always
+            // succeed if someone else created it.
+            semid = semget(key, nsems, 0);
+            if semid < 0 { return Err(Error::last_os_error()) }
+            Set::check_len(semid, len)?;
+            Set::wait_init(semid, len, opts.init_timeout)?;
+            Set::check_tag(semid, len, tag)?;
+        }
+
+        // Phew! That took long enough...
+        Ok(Set { semid, path: Some(path), len })
+    }
+
+    /// Creates a new set without a name, which can only be found through its
+    /// id.
+    pub unsafe fn anonymous(opts: &OpenOptions,
+                            initial: &[usize]) -> Result<Set> {
+        let nsems = (initial.len() + META) as libc::c_int;
+        let mode = (opts.mode & 0o777) as libc::c_int;
+        let semid = semget(IPC_PRIVATE, nsems, IPC_CREAT | mode);
+        if semid < 0 { return Err(Error::last_os_error()) }
+        Set::init(semid, initial, 0)?;
+        Ok(Set { semid, path: None, len: initial.len() })
+    }
+
+    /// Opens the existing set with the id `semid`, of which the first `len`
+    /// members are used.
+    ///
+    /// The set doesn't need to have been created by this crate, so nothing
+    /// beyond it existing is checked.
+    pub unsafe fn from_semid(semid: libc::c_int, len: usize) -> Result<Set> {
+        let mut buf: semid_ds = mem::zeroed();
+        if semctl(semid, 0, IPC_STAT, &mut buf) != 0 {
+            return Err(last_error())
+        }
+        Ok(Set { semid, path: None, len })
+    }
+
+    pub fn semid(&self) -> libc::c_int { self.semid }
+
+    pub fn len(&self) -> usize { self.len }
+
+    /// Initialize the freshly created set `semid`, removing it again if that
+    /// fails.
+    ///
+    /// Everything is set in one atomic operation, raising `INIT` last.
+    unsafe fn init(semid: libc::c_int, initial: &[usize],
+                   tag: u32) -> Result<()> {
+        let len = initial.len();
+        let mut bufs = Vec::with_capacity(len + META);
+        for (i, &n) in initial.iter().enumerate() {
+            let amt = match amount(n) {
+                Ok(amt) => amt,
+                Err(e) => { semctl(semid, 0, IPC_RMID); return Err(e) }
             };
-            // Be sure to clamp the value to 0 and then add the necessary count
-            // onto it. The clamp is necessary as the initial value seems to be
-            // generally undefined, and the bump is then necessary to modify
-            // sem_otime.
-            if semctl(semid, 0, SETVAL, 0) != 0 ||
-               semop(semid, &mut buf, 1) != 0 {
-                let err = Error::last_os_error();
-                semctl(semid, 0, IPC_RMID);
-                return Err(err)
+            bufs.push(sembuf { sem_num: i as libc::c_ushort, sem_op: amt, sem_flg: 0 });
+        }
+        bufs.push(sembuf {
+            sem_num: (len + TAG_HI) as libc::c_ushort,
+            sem_op: (tag >> 15) as libc::c_short,
+            sem_flg: 0,
+        });
+        bufs.push(sembuf {
+            sem_num: (len + TAG_LO) as libc::c_ushort,
+            sem_op: (tag & 0x7fff) as libc::c_short,
+            sem_flg: 0,
+        });
+        bufs.push(sembuf { sem_num: (len + INIT) as libc::c_ushort, sem_op: 1, sem_flg: 0 });
+        if semop(semid, bufs.as_mut_ptr(), bufs.len() as libc::c_uint) != 0 {
+            let err = Error::last_os_error();
+            semctl(semid, 0, IPC_RMID);
+            return Err(err)
+        }
+        Ok(())
+    }
+
+    /// Make sure that the existing set `semid` has exactly `len` user
+    /// members, as our bookkeeping members would be elsewhere otherwise.
+    unsafe fn check_len(semid: libc::c_int, len: usize) -> Result<()> {
+        let mut buf: semid_ds = mem::zeroed();
+        if semctl(semid, 0, IPC_STAT, &mut buf) != 0 {
+            return Err(last_error())
+        }
+        let nsems = buf.sem_nsems as usize;
+        if nsems != len + META {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("existing set has {} members, not {}",
+                                          nsems.saturating_sub(META), len)))
+        }
+        Ok(())
+    }
+
+    /// Block until the creator of the set `semid` with `len` user members
+    /// has initialized it, which it signals by raising `INIT`.
+    ///
+    /// Taking and immediately returning `INIT` in one operation only succeeds
+    /// once it has been raised, and leaves it raised for everyone else.
+    unsafe fn wait_init(semid: libc::c_int, len: usize,
+                        timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let init = (len + INIT) as libc::c_ushort;
+        loop {
+            let mut bufs = [
+                sembuf { sem_num: init, sem_op: -1, sem_flg: 0 },
+                sembuf { sem_num: init, sem_op: 1, sem_flg: 0 },
+            ];
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let timeout = libc::timespec {
+                tv_sec: remaining.as_secs() as libc::time_t,
+                tv_nsec: remaining.subsec_nanos() as libc::c_long,
+            };
+            if semtimedop(semid, bufs.as_mut_ptr(), bufs.len() as libc::c_uint,
+                          &timeout) == 0 {
+                return Ok(())
             }
-        } else {
-            match Error::last_os_error() {
-                ref e if e.raw_os_error() == Some(EEXIST) => {
-                    // Re-attempt to get the semaphore, this should in theory always
-                    // succeed?
-                    semid = semget(key, 1, 0);
-                    if semid < 0 { return Err(Error::last_os_error()) }
-
-                    // Spin in a small loop waiting for sem_otime to become not 0
-                    let mut ok = false;
-                    for _ in 0..1000 {
-                        let mut buf: semid_ds = mem::zeroed();
-                        if semctl(semid, 0, IPC_STAT, &mut buf) != 0 {
-                            return Err(Error::last_os_error())
-                        }
-                        if buf.sem_otime != 0 {
-                            ok = true;
-                            break
-                        }
-                    }
-                    if !ok {
-                        return Err(Error::new(ErrorKind::TimedOut, "timed out waiting for sem to be initialized"))
-                    }
+            match last_error() {
+                ref e if e.raw_os_error() == Some(libc::EINTR) => {}
+                ref e if e.raw_os_error() == Some(libc::EAGAIN) => {
+                    return Err(Error::new(ErrorKind::TimedOut,
+                                          "timed out waiting for sem to be initialized"))
                 }
                 e => return Err(e)
             }
         }
+    }
 
-        // Phew! That took long enough...
-        Ok(Semaphore { semid: semid })
+    /// The tag identifying the semaphore with the key file `path`.
+    ///
+    /// The directory is canonicalized first, so that it can be spelled
+    /// differently by different processes.
+    fn tag(path: &Path) -> Result<u32> {
+        let dir = fs::canonicalize(path.parent().unwrap())?;
+        let path = dir.join(path.file_name().unwrap());
+        Ok(hash::fnv1a(path.as_os_str().as_bytes()) as u32 & 0x3fff_ffff)
+    }
+
+    /// Make sure that the set `semid` with `len` user members was created for
+    /// the key file with the tag `tag`, rather than for another key file with
+    /// the same key.
+    unsafe fn check_tag(semid: libc::c_int, len: usize, tag: u32) -> Result<()> {
+        let hi = semctl(semid, (len + TAG_HI) as libc::c_int, GETVAL);
+        let lo = semctl(semid, (len + TAG_LO) as libc::c_int, GETVAL);
+        if hi < 0 || lo < 0 {
+            return Err(last_error())
+        }
+        if ((hi as u32) << 15 | lo as u32) != tag {
+            return Err(error::Error::io(error::ErrorKind::Collision, None))
+        }
+        Ok(())
     }
 
-    /// Get value hash
-    fn hash<T: Hash>(value: &T) -> u64 {
-        let mut h = SipHasher::new();
-        value.hash(&mut h);
-        h.finish()
+    pub unsafe fn key_for(name: &str, opts: &OpenOptions) -> Result<key_t> {
+        Set::key(&Set::filename(name, opts), opts.per_user, None)
+    }
+
+    pub unsafe fn remove(self) -> Result<()> {
+        if semctl(self.semid, 0, IPC_RMID) != 0 {
+            return Err(op_error(self.semid))
+        }
+        match self.path {
+            Some(ref path) => Set::remove_key(path),
+            None => Ok(()),
+        }
+    }
+
+    pub unsafe fn unlink(name: &str, opts: &OpenOptions) -> Result<()> {
+        let path = Set::filename(name, opts);
+        let key = Set::key(&path, opts.per_user, None)?;
+        let semid = semget(key, 0, 0);
+
+        // Don't remove somebody else's semaphore which happens to share our
+        // key. A set which was never initialized can't be checked, but is
+        // useless to everyone anyway.
+        if semid >= 0 {
+            let mut buf: semid_ds = mem::zeroed();
+            if semctl(semid, 0, IPC_STAT, &mut buf) == 0 &&
+               buf.sem_nsems as usize > META {
+                let len = buf.sem_nsems as usize - META;
+                if semctl(semid, (len + INIT) as libc::c_int, GETVAL) > 0 {
+                    Set::check_tag(semid, len, Set::tag(&path)?)?;
+                }
+            }
+        }
+        if semid < 0 || semctl(semid, 0, IPC_RMID) != 0 {
+            // Don't leave a stale key file behind even if the semaphore itself
+            // is already gone.
+            let err = Error::last_os_error();
+            let _ = Set::remove_key(&path);
+            return Err(err)
+        }
+        Set::remove_key(&path)
+    }
+
+    /// Delete the key file of a removed semaphore, tolerating another process
+    /// having beaten us to it.
+    fn remove_key(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
     }
 
     /// Generate the filename which will be passed to ftok, keyed off the given
     /// semaphore name `name`.
-    fn filename(name: &str) -> PathBuf {
-        let filename = name.chars().filter(|a| {
-            (*a as u32) < 128 && a.is_alphanumeric()
-        }).collect::<String>();
-        env::temp_dir().join("ipc-rs-sems").join(format!("{}-{}", filename, Semaphore::hash::<_>(&(name, "ipc-rs"))))
+    fn filename(name: &str, opts: &OpenOptions) -> PathBuf {
+        Set::dir(opts).join(mangle(name))
+    }
+
+    /// The directory holding the key files of the semaphores opened with
+    /// `opts`.
+    ///
+    /// Key files live in the directory configured through `opts`, then
+    /// `IPC_RS_DIR`, falling back to a directory in `env::temp_dir()`. With
+    /// `per_user` they are kept in a sibling directory private to the current
+    /// user instead.
+    fn dir(opts: &OpenOptions) -> PathBuf {
+        let mut dir = match opts.dir {
+            Some(ref dir) => dir.clone().into_os_string(),
+            None => match env::var_os("IPC_RS_DIR") {
+                Some(ref dir) if !dir.is_empty() => dir.clone(),
+                _ => env::temp_dir().join("ipc-rs-sems").into_os_string(),
+            },
+        };
+        if opts.per_user {
+            dir.push(format!("-{}", unsafe { libc::getuid() }));
+        }
+        PathBuf::from(dir)
     }
 
     /// Generate the `key_t` from `ftok` which will be passed to `semget`.
     ///
-    /// This function will ensure that the relevant file is located on the
-    /// filesystem and will then invoke ftok on it.
-    unsafe fn key(name: &str) -> Result<key_t> {
-        let filename = Semaphore::filename(name);
-        let dir = filename.parent().unwrap();
-
-        // As long as someone creates the directory we're alright.
-        let _ = fs::create_dir_all(&dir);
-        fs::create_dir_all(&dir).unwrap();
-
-        // Make sure that the file exists. Open it in exclusive/create mode to
-        // ensure that it's there, but don't overwrite it if it alredy exists.
-        //
-        // see QSharedMemoryPrivate::createUnixKeyFile in Qt
-        let filename = filename.to_str().unwrap().to_string() + "\0";
-        let fd = libc::open(filename.as_ptr() as (*const i8),
-                            libc::O_EXCL | libc::O_CREAT | O_RDWR,
-                            0o640);
-        if fd > 0 {
-            libc::close(fd);
-        } else {
-            match Error::last_os_error() {
-                ref e if e.raw_os_error() == Some(EEXIST) => {}
-                e => return Err(e)
+    /// If `create` is some mode this function will ensure that the relevant
+    /// file is located on the filesystem, creating it with that mode, and it
+    /// will then invoke ftok on it. Otherwise a missing file results in a
+    /// `NotFound` error.
+    unsafe fn key(filename: &Path, per_user: bool,
+                  create: Option<u32>) -> Result<key_t> {
+        Set::key_dir(filename.parent().unwrap(), per_user,
+                           create.is_some())?;
+
+        let cstr = cstring(filename)?;
+        if let Some(mode) = create {
+            // Make sure that the file exists. Open it in exclusive/create mode
+            // to ensure that it's there, but don't overwrite it if it alredy
+            // exists. This also refuses to follow a symlink planted in our
+            // way.
+            //
+            // see QSharedMemoryPrivate::createUnixKeyFile in Qt
+            let fd = libc::open(cstr.as_ptr(),
+                                libc::O_EXCL | libc::O_CREAT | O_RDWR,
+                                (mode & 0o666) as libc::mode_t);
+            if fd >= 0 {
+                libc::close(fd);
+            } else {
+                match Error::last_os_error() {
+                    ref e if e.raw_os_error() == Some(EEXIST) => {}
+                    e => return Err(e)
+                }
             }
         }
 
+        // ftok follows symlinks, so make sure that whatever is there is a
+        // plain file before handing it over.
+        if !fs::symlink_metadata(filename)?.file_type().is_file() {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("semaphore key file {} is not a \
+                                           regular file", filename.display())))
+        }
+
         // Invoke `ftok` with our filename
-        let key = ftok(filename.as_ptr(), 'I' as libc::c_int);
+        let key = ftok(cstr.as_ptr() as *const libc::c_uchar, 'I' as libc::c_int);
         if key != -1 {Ok(key)} else {Err(Error::last_os_error())}
     }
 
-    pub unsafe fn wait(&self) {
+    /// Make sure that the directory `dir` holding key files can be trusted,
+    /// creating it first if `create` is set.
+    ///
+    /// Anyone able to replace files in the directory could point our key files
+    /// at files of their choosing, so the directory must be a real directory
+    /// owned by us or root. A shared directory is created world-writable but
+    /// sticky like `/tmp` so users can't interfere with each other's files,
+    /// and a per-user directory must be inaccessible to everyone else.
+    unsafe fn key_dir(dir: &Path, per_user: bool, create: bool) -> Result<()> {
+        let mode = if per_user {0o700} else {0o1777};
+        if create {
+            if let Some(parent) = dir.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs::DirBuilder::new().mode(mode).create(dir) {
+                // The umask has probably masked off some of the bits we want.
+                Ok(()) => fs::set_permissions(dir, fs::Permissions::from_mode(mode))?,
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let meta = fs::symlink_metadata(dir)?;
+        let uid = libc::getuid() as u32;
+        let dir_mode = meta.mode();
+        // The owner of a directory can always rename or remove its entries,
+        // sticky or not, so it has to be us or root either way.
+        let secure = meta.file_type().is_dir() && if per_user {
+            meta.uid() == uid && dir_mode & 0o077 == 0
+        } else {
+            (meta.uid() == uid || meta.uid() == 0) &&
+                (dir_mode & 0o022 == 0 || dir_mode & 0o1000 != 0)
+        };
+        if !secure {
+            return Err(Error::new(ErrorKind::PermissionDenied,
+                                  format!("refusing to use insecure semaphore \
+                                           key directory {}", dir.display())))
+        }
+        Ok(())
+    }
+
+    /// Apply all of `ops` in one atomic step, returning whether they were
+    /// applied.
+    ///
+    /// If `wait` is false this never blocks, otherwise it blocks until
+    /// `deadline` if there is one.
+    pub unsafe fn apply(&self, ops: &mut [sembuf], wait: bool,
+                        deadline: Option<Instant>) -> Result<bool> {
+        if ops.is_empty() { return Ok(true) }
+        if !wait {
+            for op in ops.iter_mut() {
+                op.sem_flg |= IPC_NOWAIT;
+            }
+        }
         loop {
-            if self.modify(-1, true) == 0 { return }
+            let rc = match deadline {
+                // Each time we're interrupted recompute how much time is left
+                // so signals don't extend the overall deadline.
+                Some(deadline) if wait => {
+                    let dur = deadline.saturating_duration_since(Instant::now());
+                    let timeout = libc::timespec {
+                        tv_sec: dur.as_secs() as libc::time_t,
+                        tv_nsec: dur.subsec_nanos() as libc::c_long,
+                    };
+                    semtimedop(self.semid, ops.as_mut_ptr(),
+                               ops.len() as libc::c_uint, &timeout)
+                }
+                _ => semop(self.semid, ops.as_mut_ptr(), ops.len() as libc::c_uint),
+            };
+            if rc == 0 { return Ok(true) }
 
-            match Error::last_os_error() {
+            match op_error(self.semid) {
                 ref e if e.raw_os_error() == Some(libc::EINTR) => {}
-                e => panic!("unknown wait error: {}", e)
+                ref e if e.raw_os_error() == Some(libc::EAGAIN) &&
+                         (!wait || deadline.is_some()) => return Ok(false),
+                e => return Err(e)
             }
         }
     }
 
-    pub unsafe fn try_wait(&self) -> bool {
-        if self.modify(-1, false) == 0 { return true }
+    pub unsafe fn set_permissions(&self, mode: u32) -> Result<()> {
+        self.set(|perm| perm.mode = (mode & 0o777) as _)?;
+        // Keep the key file in sync, but it only needs to be found by others
+        // so it never needs to be executable.
+        match self.path {
+            Some(ref path) => {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o666))
+            }
+            None => Ok(()),
+        }
+    }
 
-        match Error::last_os_error() {
-            ref e if e.raw_os_error() == Some(libc::EAGAIN) => return false,
-            e => panic!("unknown try_wait error: {}", e)
+    pub unsafe fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        self.set(|perm| {
+            perm.uid = uid as libc::uid_t;
+            perm.gid = gid as libc::gid_t;
+        })
+    }
+
+    /// Read the current `ipc_perm` of this semaphore, let `f` modify it, and
+    /// write it back with `IPC_SET`.
+    unsafe fn set<F: FnOnce(&mut ipc_perm)>(&self, f: F) -> Result<()> {
+        let mut buf: semid_ds = mem::zeroed();
+        if semctl(self.semid, 0, IPC_STAT, &mut buf) != 0 {
+            return Err(op_error(self.semid))
+        }
+        f(&mut buf.sem_perm);
+        if semctl(self.semid, 0, IPC_SET, &mut buf) != 0 {
+            return Err(op_error(self.semid))
         }
+        Ok(())
     }
 
-    pub unsafe fn post(&self) {
-        if self.modify(1, true) == 0 { return }
-        panic!("unknown post error: {}", Error::last_os_error())
+    pub unsafe fn stats(&self, num: usize) -> Result<SemaphoreStats> {
+        Ok(SemaphoreStats {
+            value: self.get(num, GETVAL)? as usize,
+            waiters: self.get(num, GETNCNT)? as usize,
+            zero_waiters: self.get(num, GETZCNT)? as usize,
+            last_pid: self.get(num, GETPID)? as u32,
+        })
     }
 
-    unsafe fn modify(&self, amt: i16, wait: bool) -> libc::c_int {
-        let mut buf = sembuf {
-            sem_num: 0,
-            sem_op: amt as libc::c_short,
-            sem_flg: if wait {0} else {IPC_NOWAIT} | SEM_UNDO,
-        };
-        semop(self.semid, &mut buf, 1)
+    /// Run one of the `GET*` commands of `semctl` on the member `num`, which
+    /// all return their value directly.
+    pub unsafe fn get(&self, num: usize, cmd: libc::c_int) -> Result<libc::c_int> {
+        match semctl(self.semid, num as libc::c_int, cmd) {
+            -1 => Err(op_error(self.semid)),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Semaphore {
+    pub unsafe fn new(name: &str, opts: &OpenOptions) -> Result<Semaphore> {
+        Ok(Semaphore { set: Set::new(name, opts, &[opts.initial])? })
+    }
+
+    pub unsafe fn anonymous(opts: &OpenOptions) -> Result<Semaphore> {
+        Ok(Semaphore { set: Set::anonymous(opts, &[opts.initial])? })
+    }
+
+    pub unsafe fn from_semid(semid: libc::c_int) -> Result<Semaphore> {
+        Ok(Semaphore { set: Set::from_semid(semid, 1)? })
+    }
+
+    pub fn semid(&self) -> libc::c_int { self.set.semid() }
+
+    pub unsafe fn key_for(name: &str, opts: &OpenOptions) -> Result<key_t> {
+        Set::key_for(name, opts)
+    }
+
+    pub unsafe fn remove(self) -> Result<()> { self.set.remove() }
+
+    pub unsafe fn unlink(name: &str, opts: &OpenOptions) -> Result<()> {
+        Set::unlink(name, opts)
+    }
+
+    pub unsafe fn wait(&self, n: usize, deadline: Option<Instant>,
+                       undo: bool) -> Result<bool> {
+        // A `sem_op` of 0 means "wait for zero", so don't pass that through.
+        if n == 0 { return Ok(true) }
+        let mut op = Semaphore::op(-amount(n)?, undo);
+        self.set.apply(&mut op, true, deadline)
+    }
+
+    pub unsafe fn try_wait(&self, n: usize, undo: bool) -> Result<bool> {
+        if n == 0 { return Ok(true) }
+        let mut op = Semaphore::op(-amount(n)?, undo);
+        self.set.apply(&mut op, false, None)
+    }
+
+    pub unsafe fn wait_zero(&self, deadline: Option<Instant>) -> Result<bool> {
+        self.set.apply(&mut Semaphore::op(0, false), true, deadline)
+    }
+
+    pub unsafe fn try_wait_zero(&self) -> Result<bool> {
+        self.set.apply(&mut Semaphore::op(0, false), false, None)
+    }
+
+    pub unsafe fn post(&self, n: usize, undo: bool) -> Result<()> {
+        if n == 0 { return Ok(()) }
+        let mut op = Semaphore::op(amount(n)?, undo);
+        self.set.apply(&mut op, true, None).map(|_| ())
+    }
+
+    fn op(amt: libc::c_short, undo: bool) -> [sembuf; 1] {
+        [sembuf { sem_num: 0, sem_op: amt, sem_flg: if undo {SEM_UNDO} else {0} }]
+    }
+
+    pub unsafe fn set_permissions(&self, mode: u32) -> Result<()> {
+        self.set.set_permissions(mode)
+    }
+
+    pub unsafe fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        self.set.set_owner(uid, gid)
+    }
+
+    pub unsafe fn stats(&self) -> Result<SemaphoreStats> {
+        self.set.stats(0)
+    }
+
+    pub unsafe fn value(&self) -> Result<usize> {
+        self.set.get(0, GETVAL).map(|v| v as usize)
+    }
+}
+
+/// Mangle the user-provided name `name` into something safe to use as a file
+/// name, keeping the alphanumeric characters for readability and appending a
+/// hash of the whole name to keep distinct names distinct.
+///
+/// This is documented in `OpenOptions::key_for` and must not change without
+/// bumping the version embedded in the result.
+pub fn mangle(name: &str) -> String {
+    let filename = name.chars().filter(|a| {
+        (*a as u32) < 128 && a.is_alphanumeric()
+    }).collect::<String>();
+    format!("{}-v1-{:016x}", filename, hash::fnv1a(name.as_bytes()))
+}
+
+/// Returns the directory holding the key files of the semaphores opened with
+/// `opts`, for keeping other objects apart in the same way.
+///
+/// The directory is checked as when opening a semaphore, and created first if
+/// `create` is true. The path is canonicalized so that it is the same however
+/// the directory was spelled.
+pub unsafe fn key_dir(opts: &OpenOptions, create: bool) -> Result<PathBuf> {
+    let dir = Set::dir(opts);
+    Set::key_dir(&dir, opts.per_user, create)?;
+    fs::canonicalize(dir)
+}
+
+/// Convert `path` to a C string for passing to the system.
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "path contains a nul byte")
+    })
+}
+
+/// Convert a number of permits into a `sem_op` value.
+///
+/// The kernel applies the whole delta in one step, but it has to fit in the
+/// `short` of a `sembuf` (and in practice below `SEMVMX`).
+fn amount(n: usize) -> Result<libc::c_short> {
+    if n > i16::MAX as usize {
+        return Err(error::Error::io(error::ErrorKind::Overflow, None))
     }
+    Ok(n as libc::c_short)
+}
+
+/// Returns the last OS error, wrapping the errors which callers may want to
+/// recover from in an `error::Error`.
+fn last_error() -> Error {
+    let err = Error::last_os_error();
+    let kind = match err.raw_os_error() {
+        Some(libc::EIDRM) => error::ErrorKind::Removed,
+        Some(libc::EACCES) => error::ErrorKind::PermissionDenied,
+        Some(libc::ERANGE) => error::ErrorKind::Overflow,
+        _ => return err,
+    };
+    error::Error::io(kind, err.raw_os_error())
 }
 
-impl Drop for Semaphore {
-    fn drop(&mut self) {}
+/// Returns the last OS error of an operation on the set `semid`, which we
+/// already have open, like `last_error`.
+///
+/// Operating on a removed set fails with `EIDRM` if we were blocked on it at
+/// the time, and with `EINVAL` otherwise as its id is no longer valid. But
+/// `EINVAL` is also returned for invalid arguments, like more operations than
+/// `SEMOPM` at once, so it only means that the set was removed if the id
+/// turns out to be invalid now.
+unsafe fn op_error(semid: libc::c_int) -> Error {
+    let err = last_error();
+    if err.raw_os_error() == Some(libc::EINVAL) {
+        let mut buf: semid_ds = mem::zeroed();
+        if semctl(semid, 0, IPC_STAT, &mut buf) != 0 &&
+           Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+            return error::Error::io(error::ErrorKind::Removed, Some(libc::EINVAL))
+        }
+    }
+    err
 }
 
 #[cfg(test)]
 mod tests {
     extern crate tempdir;
 
-    use std::fs::File;
-    use std::io::Write;
+    use std::fs::{self, File};
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
     use std::process::Command;
     use std::str;
     use std::mem;
 
     use self::tempdir::TempDir;
 
-    use super::consts::{sembuf, semid_ds, ipc_perm};
+    use super::consts::{sembuf, semid_ds, ipc_perm, IPC_STAT};
+    use super::{semctl, Semaphore, Set};
+    use libc;
+    use OpenOptions;
+
+    fn mode(sem: &Semaphore) -> u32 {
+        unsafe {
+            let mut buf: semid_ds = mem::zeroed();
+            assert_eq!(semctl(sem.set.semid, 0, IPC_STAT, &mut buf), 0);
+            buf.sem_perm.mode as u32 & 0o777
+        }
+    }
+
+    #[test]
+    fn permissions() {
+        let mut opts = OpenOptions::new();
+        let _ = unsafe { Semaphore::unlink("unix-permissions", &opts) };
+        opts.mode(0o600);
+        let s = unsafe { Semaphore::new("unix-permissions", &opts).unwrap() };
+        assert_eq!(mode(&s), 0o600);
+        let file_mode = fs::metadata(s.set.path.as_ref().unwrap()).unwrap().permissions().mode();
+        assert_eq!(file_mode & 0o077, 0);
+
+        unsafe {
+            s.set_permissions(0o640).unwrap();
+            s.set_owner(libc::getuid(), libc::getgid()).unwrap();
+        }
+        assert_eq!(mode(&s), 0o640);
+        let file_mode = fs::metadata(s.set.path.as_ref().unwrap()).unwrap().permissions().mode();
+        assert_eq!(file_mode & 0o777, 0o640);
+        unsafe { s.remove().unwrap() }
+    }
+
+    #[test]
+    fn dir() {
+        let td = TempDir::new("dir").unwrap();
+        let mut opts = OpenOptions::new();
+        opts.dir(td.path().join("sems"));
+        let s = unsafe { Semaphore::new("unix-dir", &opts).unwrap() };
+        assert_eq!(s.set.path.as_ref().unwrap().parent().unwrap(), td.path().join("sems"));
+
+        // another spelling of the same directory finds the same semaphore
+        let mut other = opts.clone();
+        other.dir(td.path().join(".").join("sems"));
+        let s2 = unsafe { Semaphore::new("unix-dir", &other).unwrap() };
+        assert_eq!(s2.set.semid, s.set.semid);
+        unsafe { s.remove().unwrap() }
+
+        opts.per_user(true);
+        let s = unsafe { Semaphore::new("unix-dir", &opts).unwrap() };
+        let dir = format!("sems-{}", unsafe { libc::getuid() });
+        assert_eq!(s.set.path.as_ref().unwrap().parent().unwrap(), td.path().join(dir));
+        unsafe { s.remove().unwrap() }
+    }
+
+    #[test]
+    fn collision() {
+        let opts = OpenOptions::new();
+        let a = unsafe { Semaphore::new("unix-collision-a", &opts).unwrap() };
+
+        // Hard links share an inode, so this gives the second name the same
+        // key as the first.
+        let path = Set::filename("unix-collision-b", &opts);
+        let _ = fs::remove_file(&path);
+        fs::hard_link(a.set.path.as_ref().unwrap(), &path).unwrap();
+        let err = unsafe { Semaphore::new("unix-collision-b", &opts).err().unwrap() };
+        assert_eq!(::Error::from_io(&err).unwrap().kind(), ::ErrorKind::Collision);
+        let err = unsafe { Semaphore::unlink("unix-collision-b", &opts).unwrap_err() };
+        assert_eq!(::Error::from_io(&err).unwrap().kind(), ::ErrorKind::Collision);
+
+        fs::remove_file(&path).unwrap();
+        unsafe { a.remove().unwrap() }
+
+        // The same name in another directory is a different semaphore too.
+        let td = TempDir::new("collision").unwrap();
+        let mut opts = OpenOptions::new();
+        opts.dir(td.path().join("a"));
+        let a = unsafe { Semaphore::new("unix-collision", &opts).unwrap() };
+        let mut other = opts.clone();
+        other.dir(td.path().join("b"));
+        let path = Set::filename("unix-collision", &other);
+        unsafe { Set::key_dir(path.parent().unwrap(), false, true).unwrap() };
+        fs::hard_link(a.set.path.as_ref().unwrap(), &path).unwrap();
+        let err = unsafe { Semaphore::new("unix-collision", &other).err().unwrap() };
+        assert_eq!(::Error::from_io(&err).unwrap().kind(), ::ErrorKind::Collision);
+        fs::remove_file(&path).unwrap();
+        unsafe { a.remove().unwrap() }
+    }
+
+    #[test]
+    fn key_scheme() {
+        use hash::fnv1a;
+
+        // These are part of the documented scheme, so must never change.
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(super::mangle("foo"), "foo-v1-dcb27518fed9d577");
+        assert_eq!(super::mangle("a/b c"), format!("abc-v1-{:016x}",
+                                                   fnv1a(b"a/b c")));
+
+        let opts = OpenOptions::new();
+        let s = unsafe { Semaphore::new("unix-key-scheme", &opts).unwrap() };
+        let path = format!("{}\0", s.set.path.as_ref().unwrap().display());
+        let key = unsafe {
+            super::ftok(path.as_ptr(), 'I' as libc::c_int)
+        };
+        assert_eq!(unsafe { Semaphore::key_for("unix-key-scheme", &opts).unwrap() },
+                   key);
+        unsafe { s.remove().unwrap() }
+    }
+
+    #[test]
+    fn foreign() {
+        use super::semget;
+        use super::consts::{IPC_CREAT, IPC_PRIVATE, IPC_RMID};
+
+        // A set created by somebody else, who only knows about one member.
+        let semid = unsafe { semget(IPC_PRIVATE, 1, IPC_CREAT | 0o600) };
+        assert!(semid >= 0);
+        let s = unsafe { Semaphore::from_semid(semid).unwrap() };
+        unsafe {
+            s.post(2, false).unwrap();
+            assert!(s.try_wait(1, false).unwrap());
+            assert_eq!(s.value().unwrap(), 1);
+            assert_eq!(semctl(semid, 0, IPC_RMID), 0);
+            assert!(Semaphore::from_semid(semid).is_err());
+        }
+    }
+
+    #[test]
+    fn key_dir() {
+        use std::os::unix::fs::{chown, symlink};
+
+        let td = TempDir::new("key_dir").unwrap();
+        let shared = td.path().join("shared");
+        let private = td.path().join("private");
+        unsafe {
+            Set::key_dir(&shared, false, true).unwrap();
+            Set::key_dir(&private, true, true).unwrap();
+        }
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&shared), 0o1777);
+        assert_eq!(mode(&private), 0o700);
+
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o755)).unwrap();
+        let err = unsafe { Set::key_dir(&private, true, false).unwrap_err() };
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        let err = unsafe { Set::key_dir(&shared, false, false).unwrap_err() };
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        // somebody else's shared directory is refused even if it's sticky
+        if unsafe { libc::getuid() } == 0 {
+            chown(&shared, Some(65534), Some(65534)).unwrap();
+            let err = unsafe { Set::key_dir(&shared, false, true).unwrap_err() };
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            chown(&shared, Some(0), Some(0)).unwrap();
+        }
+
+        let link = td.path().join("link");
+        symlink(&shared, &link).unwrap();
+        let err = unsafe { Set::key_dir(&link, false, true).unwrap_err() };
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        let planted = shared.join("planted");
+        symlink(td.path().join("target"), &planted).unwrap();
+        let err = unsafe { Set::key(&planted, false, Some(0o600)).unwrap_err() };
+        assert!(!td.path().join("target").exists(), "{}", err);
+    }
 
-    macro_rules! offset{ ($ty:ty, $f:ident) => (unsafe {
-        let f = 0 as *const $ty;
-        &(*f).$f as *const _ as usize
-    }) }
+    macro_rules! offset{ ($ty:ty, $f:ident) => (mem::offset_of!($ty, $f)) }
 
     #[test]
     fn check_offsets() {
         let td = TempDir::new("test").unwrap();
-        let mut f = File::create(&td.path().join("foo.c")).unwrap();
-        f.write(&format!(r#"
+        let mut f = File::create(td.path().join("foo.c")).unwrap();
+        f.write_all(&format!(r#"
 #include <assert.h>
 #include <stdio.h>
 #include <stddef.h>
 #include <stdlib.h>
 #include <unistd.h>
 #include <errno.h>
+#include <fcntl.h>
 #include <sys/types.h>
 #include <sys/ipc.h>
 #include <sys/sem.h>
@@ -357,13 +1030,18 @@ int main() {{
     assert_eq(offsetof(struct semid_ds, sem_nsems), {sem_nsems});
     assert_eq(sizeof(struct semid_ds), {semid_ds});
 
+    assert_eq(IPC_PRIVATE, {IPC_PRIVATE});
     assert_eq(IPC_CREAT, {IPC_CREAT});
     assert_eq(IPC_EXCL, {IPC_EXCL});
     assert_eq(IPC_NOWAIT, {IPC_NOWAIT});
     assert_eq(SEM_UNDO, {SEM_UNDO});
-    assert_eq(SETVAL, {SETVAL});
+    assert_eq(GETPID, {GETPID});
+    assert_eq(GETVAL, {GETVAL});
+    assert_eq(GETNCNT, {GETNCNT});
+    assert_eq(GETZCNT, {GETZCNT});
     assert_eq(IPC_STAT, {IPC_STAT});
     assert_eq(IPC_RMID, {IPC_RMID});
+    assert_eq(IPC_SET, {IPC_SET});
     return 0;
 }}
 
@@ -388,19 +1066,24 @@ int main() {{
     sem_nsems = offset!(semid_ds, sem_nsems),
     semid_ds = mem::size_of::<semid_ds>(),
 
+    IPC_PRIVATE = super::consts::IPC_PRIVATE,
     IPC_CREAT = super::consts::IPC_CREAT,
     IPC_EXCL = super::consts::IPC_EXCL,
     IPC_NOWAIT = super::consts::IPC_NOWAIT,
     SEM_UNDO = super::consts::SEM_UNDO,
-    SETVAL = super::consts::SETVAL,
+    GETPID = super::consts::GETPID,
+    GETVAL = super::consts::GETVAL,
+    GETNCNT = super::consts::GETNCNT,
+    GETZCNT = super::consts::GETZCNT,
     IPC_STAT = super::consts::IPC_STAT,
     IPC_RMID = super::consts::IPC_RMID,
+    IPC_SET = super::consts::IPC_SET,
 
     key = if cfg!(target_os = "macos") {"_key"} else {"__key"},
     seq = if cfg!(target_os = "macos") {"_seq"} else {"__seq"},
 ).into_bytes()).unwrap();
 
-        let arg = if cfg!(target_word_size = "32") {"-m32"} else {"-m64"};
+        let arg = if cfg!(target_pointer_width = "32") {"-m32"} else {"-m64"};
         let s = Command::new("gcc").arg("-o").arg(td.path().join("foo"))
                                    .arg(td.path().join("foo.c"))
                                    .arg(arg).output().unwrap();
@@ -1,12 +1,18 @@
 use libc;
 use std::i32;
-use std::hash::{Hash, Hasher, SipHasher};
-use std::io::{Result, Error};
+use std::io::{Result, Error, ErrorKind};
+use std::time::Instant;
+
+use {error, hash, OpenOptions, SemaphoreStats};
 
 pub struct Semaphore { handle: libc::HANDLE }
 
 pub const WAIT_FAILED: libc::DWORD = 0xFFFFFFFF;
 pub const WAIT_TIMEOUT: libc::DWORD = 0x00000102;
+pub const SEMAPHORE_ALL_ACCESS: libc::DWORD = 0x001F0003;
+pub const ERROR_ACCESS_DENIED: i32 = 5;
+pub const ERROR_ALREADY_EXISTS: i32 = 183;
+pub const ERROR_TOO_MANY_POSTS: i32 = 298;
 
 extern "system" {
     fn CreateSemaphoreW(lpSemaphoreAttributes: libc::LPSECURITY_ATTRIBUTES,
@@ -16,55 +22,154 @@ extern "system" {
     fn ReleaseSemaphore(hSemaphore: libc::HANDLE,
                         lReleaseCount: libc::LONG,
                         lpPreviousCount: *mut libc::LONG) -> libc::BOOL;
+    fn OpenSemaphoreW(dwDesiredAccess: libc::DWORD,
+                      bInheritHandle: libc::BOOL,
+                      lpName: libc::LPCWSTR) -> libc::HANDLE;
 }
 
 impl Semaphore {
-    /// Get value hash
-    fn hash<T: Hash>(value: &T) -> u64 {
-        let mut h = SipHasher::new();
-        value.hash(&mut h);
-        h.finish()
-    }
-
-    pub unsafe fn new(name: &str, cnt: usize) -> Result<Semaphore> {
-        let name = format!(r"Global\{}-{}", name.replace(r"\", ""),
-                           Semaphore::hash::<_>(&(name, "ipc-rs")));
+    /// Generate the name of the kernel object for the semaphore `name`.
+    fn wide_name(name: &str) -> Vec<u16> {
+        let name = format!(r"Global\{}-v1-{:016x}", name.replace(r"\", ""),
+                           hash::fnv1a(name.as_bytes()));
         let mut name = name.bytes().map(|b| b as u16).collect::<Vec<u16>>();
         name.push(0);
-        let handle = CreateSemaphoreW(0 as *mut _,
-                                      cnt as libc::LONG,
-                                      i32::MAX as libc::LONG,
-                                      name.as_ptr());
-        if handle.is_null() {
-            Err(Error::last_os_error())
+        name
+    }
+
+    pub unsafe fn new(name: &str, opts: &OpenOptions) -> Result<Semaphore> {
+        let name = Semaphore::wide_name(name);
+        let handle = if opts.create || opts.create_new {
+            CreateSemaphoreW(0 as *mut _,
+                             opts.initial as libc::LONG,
+                             i32::MAX as libc::LONG,
+                             name.as_ptr())
         } else {
-            Ok(Semaphore { handle: handle })
+            OpenSemaphoreW(SEMAPHORE_ALL_ACCESS, 0, name.as_ptr())
+        };
+        if handle.is_null() {
+            return Err(Error::last_os_error())
+        }
+        // CreateSemaphoreW happily opens an existing semaphore, but tells us
+        // that it did so.
+        let err = Error::last_os_error();
+        let sem = Semaphore { handle: handle };
+        if opts.create_new && err.raw_os_error() == Some(ERROR_ALREADY_EXISTS) {
+            return Err(Error::new(ErrorKind::AlreadyExists,
+                                  "semaphore already exists"))
         }
+        Ok(sem)
     }
 
-    pub unsafe fn wait(&self) {
-        match libc::WaitForSingleObject(self.handle, libc::INFINITE) {
-            libc::WAIT_OBJECT_0 => {},
-            WAIT_FAILED => panic!("failed to wait: {}", Error::last_os_error()),
-            n => panic!("bad wait(): {}/{}", n, Error::last_os_error()),
+    pub unsafe fn remove(self) -> Result<()> {
+        // Named kernel objects are destroyed once the last handle to them is
+        // closed, so all we can do is close ours.
+        drop(self);
+        Ok(())
+    }
+
+    pub unsafe fn unlink(_name: &str, _opts: &OpenOptions) -> Result<()> {
+        // A semaphore only goes away once every handle to it is closed, which
+        // we can't do for other processes.
+        Err(Error::new(ErrorKind::Other,
+                       "removing semaphores by name is not supported on windows"))
+    }
+
+    pub unsafe fn wait(&self, n: usize, deadline: Option<Instant>,
+                       _undo: bool) -> Result<bool> {
+        // Windows has no way to take several counts of a semaphore at once, so
+        // take them one at a time and hand back what we got if we time out.
+        for i in 0..n {
+            let ms = match deadline {
+                Some(deadline) => timeout_ms(deadline),
+                None => libc::INFINITE,
+            };
+            match self.wait_ms(ms) {
+                Ok(true) => {}
+                res => { let _ = self.post(i, false); return res }
+            }
         }
+        Ok(true)
     }
 
-    pub unsafe fn try_wait(&self) -> bool {
-        match libc::WaitForSingleObject(self.handle, 0) {
-            libc::WAIT_OBJECT_0 => true,
-            WAIT_TIMEOUT => false,
-            WAIT_FAILED => panic!("failed to wait: {}", Error::last_os_error()),
-            n => panic!("bad wait(): {}/{}", n, Error::last_os_error()),
+    pub unsafe fn try_wait(&self, n: usize, _undo: bool) -> Result<bool> {
+        for i in 0..n {
+            match self.wait_ms(0) {
+                Ok(true) => {}
+                res => { let _ = self.post(i, false); return res }
+            }
         }
+        Ok(true)
     }
 
-    pub unsafe fn post(&self) {
-        match ReleaseSemaphore(self.handle, 1, 0 as *mut _) {
-            0 => panic!("failed to release semaphore: {}", Error::last_os_error()),
-            _ => {}
+    unsafe fn wait_ms(&self, ms: libc::DWORD) -> Result<bool> {
+        match libc::WaitForSingleObject(self.handle, ms) {
+            libc::WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            WAIT_FAILED => Err(last_error()),
+            n => Err(Error::new(ErrorKind::Other,
+                                format!("bad wait(): {}", n))),
         }
     }
+
+    pub unsafe fn set_permissions(&self, _mode: u32) -> Result<()> {
+        Err(Error::new(ErrorKind::Other,
+                       "semaphore permissions are not supported on windows"))
+    }
+
+    pub unsafe fn set_owner(&self, _uid: u32, _gid: u32) -> Result<()> {
+        Err(Error::new(ErrorKind::Other,
+                       "semaphore ownership is not supported on windows"))
+    }
+
+    pub unsafe fn stats(&self) -> Result<SemaphoreStats> {
+        Err(Error::new(ErrorKind::Other,
+                       "semaphore statistics are not available on windows"))
+    }
+
+    pub unsafe fn value(&self) -> Result<usize> {
+        Err(Error::new(ErrorKind::Other,
+                       "semaphore values are not available on windows"))
+    }
+
+    pub unsafe fn wait_zero(&self, _deadline: Option<Instant>) -> Result<bool> {
+        Err(Error::new(ErrorKind::Other,
+                       "waiting for zero is not supported on windows"))
+    }
+
+    pub unsafe fn try_wait_zero(&self) -> Result<bool> {
+        Err(Error::new(ErrorKind::Other,
+                       "waiting for zero is not supported on windows"))
+    }
+
+    pub unsafe fn post(&self, n: usize, _undo: bool) -> Result<()> {
+        if n == 0 { return Ok(()) }
+        match ReleaseSemaphore(self.handle, n as libc::LONG, 0 as *mut _) {
+            0 => Err(last_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the last OS error, wrapping the errors which callers may want to
+/// recover from in an `error::Error`.
+fn last_error() -> Error {
+    let err = Error::last_os_error();
+    let kind = match err.raw_os_error() {
+        Some(ERROR_ACCESS_DENIED) => error::ErrorKind::PermissionDenied,
+        Some(ERROR_TOO_MANY_POSTS) => error::ErrorKind::Overflow,
+        _ => return err,
+    };
+    error::Error::io(kind, err.raw_os_error())
+}
+
+/// Milliseconds remaining until `deadline`, rounded up to the next millisecond
+/// and never accidentally asking for an INFINITE wait.
+fn timeout_ms(deadline: Instant) -> libc::DWORD {
+    let dur = deadline.saturating_duration_since(Instant::now());
+    let ms = dur.as_secs().saturating_mul(1000)
+                .saturating_add((dur.subsec_nanos() as u64 + 999_999) / 1_000_000);
+    ::std::cmp::min(ms, (libc::INFINITE - 1) as u64) as libc::DWORD
 }
 
 unsafe impl Send for Semaphore {}
@@ -3,8 +3,9 @@
 extern crate ipc;
 
 use std::env;
-use std::process::Command;
+use std::process::{self, Command, Stdio};
 use std::str;
+use std::time::Duration;
 
 fn main() {
     let mut args = env::args();
@@ -17,13 +18,83 @@ fn main() {
         match &arg as &str {
             "test1_inner" => {
                 let sem1 = ipc::Semaphore::new("foo1", 0).unwrap();
-                let sem2 = ipc::Semaphore::new("foo2", 0).unwrap();
+                let sem2 = signal("foo2");
                 println!("[1] Unlock foo2");
-                let _ = sem2.release();
-                let _ = sem1.access();
+                sem2.release().unwrap();
+                drop(sem1.access().unwrap());
                 println!("[1] Lock foo1");
             }
             "test1" => first_pass(),
+            "test2_inner" => {
+                println!("[1] Release handoff");
+                signal("handoff").release().unwrap();
+            }
+            "test2" => handoff(),
+            "test3_inner" => {
+                // Every racer either creates the semaphore or waits for its
+                // creator, and must never see it uninitialized.
+                let sem = ipc::Semaphore::options().initial(RACE_COUNT)
+                                                   .open("race").unwrap();
+                drop(sem.access().unwrap());
+            }
+            "test3" => race(),
+            #[cfg(unix)]
+            "test4_inner" => {
+                println!("[1] Release anonymous");
+                ipc::Semaphore::options().semantics(ipc::Semantics::Signal)
+                                         .open_inherited(ANONYMOUS_VAR).unwrap()
+                                         .release().unwrap();
+            }
+            #[cfg(unix)]
+            "test4" => anonymous(),
+            #[cfg(unix)]
+            "test5_inner" => {
+                ipc::Latch::new("latch", WORKERS).unwrap().count_down().unwrap();
+            }
+            #[cfg(unix)]
+            "test5" => latch(),
+            #[cfg(unix)]
+            "test6_inner" => {
+                let counter = ipc::Mutex::new("counter", 0u64).unwrap();
+                for _ in 0..INCREMENTS {
+                    *counter.lock().unwrap() += 1;
+                }
+            }
+            #[cfg(unix)]
+            "test6" => counter(),
+            #[cfg(unix)]
+            "test7_inner" => {
+                let m = ipc::Mutex::new("robust", [0u32; 2]).unwrap();
+                let mut g = m.lock().unwrap();
+                g[0] = 1;
+                println!("[1] Die holding robust");
+                // Exiting skips destructors, so the guard never unlocks.
+                process::exit(0);
+            }
+            #[cfg(unix)]
+            "test7" => robust(),
+            #[cfg(all(target_os = "linux",
+                      any(target_arch = "x86", target_arch = "x86_64",
+                          target_arch = "arm", target_arch = "aarch64")))]
+            "test8_inner" => {
+                let m = ipc::Mutex::new("condvar", 0u32).unwrap();
+                let c = ipc::Condvar::new("condvar").unwrap();
+                println!("[1] Notify condvar");
+                *m.lock().unwrap() = 1;
+                c.notify_all().unwrap();
+            }
+            #[cfg(all(target_os = "linux",
+                      any(target_arch = "x86", target_arch = "x86_64",
+                          target_arch = "arm", target_arch = "aarch64")))]
+            "test8" => condvar(),
+            #[cfg(unix)]
+            "test9_inner" => {
+                // Report how often we led through the exit code.
+                process::exit(meet(&ipc::Barrier::new("barrier", WORKERS + 1)
+                                                  .unwrap()) as i32);
+            }
+            #[cfg(unix)]
+            "test9" => barrier(),
             v => panic!("Unknown test: {}", v),
         }
         println!("Leave: {}", arg);
@@ -34,28 +105,192 @@ fn me() -> Command {
     Command::new(env::current_exe().unwrap())
 }
 
+/// Open a semaphore used to pass events between processes, which must not be
+/// undone when the process which released it exits.
+fn signal(name: &str) -> ipc::Semaphore {
+    ipc::Semaphore::options().semantics(ipc::Semantics::Signal).open(name).unwrap()
+}
+
 fn first_pass() {
     let sem1 = ipc::Semaphore::new("foo1", 1).unwrap();
-    let sem2 = ipc::Semaphore::new("foo2", 0).unwrap();
+    let sem2 = signal("foo2");
     println!("[0] Lock foo1");
-    let g1 = sem1.access();
+    let g1 = sem1.access().unwrap();
     println!("[0] Start");
     let mut p = me().arg("test1_inner").spawn().unwrap();
-    sem2.acquire();
+    sem2.acquire().unwrap();
     println!("[0] Lock foo2");
     println!("[0] Unlock foo1");
     drop(g1);
     p.wait().unwrap();
     println!("[0] Join");
-    drop(sem1.access());
+    drop(sem1.access().unwrap());
 }
 
-fn run_test() {
-    let test_exe = env::current_exe().unwrap();
-    let output = Command::new(test_exe).arg("test1").output().unwrap();
+fn handoff() {
+    let _ = ipc::Semaphore::unlink("handoff");
+    let sem = signal("handoff");
+    assert!(me().arg("test2_inner").status().unwrap().success());
+    println!("[0] Join");
+    assert!(sem.try_acquire().unwrap());
+    println!("[0] Acquire handoff");
+    sem.remove().unwrap();
+}
+
+const RACERS: usize = 32;
+const RACE_COUNT: usize = 3;
+
+fn race() {
+    let _ = ipc::Semaphore::unlink("race");
+    let children = (0..RACERS).map(|_| {
+        me().arg("test3_inner").stdout(Stdio::null()).spawn().unwrap()
+    }).collect::<Vec<_>>();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    println!("[0] Join racers");
+    let sem = ipc::Semaphore::options().create(false).open("race").unwrap();
+    // only System V semaphores can be read without acquiring them
+    #[cfg(unix)]
+    assert_eq!(sem.value().unwrap(), RACE_COUNT);
+    sem.remove().unwrap();
+}
+
+#[cfg(unix)]
+const ANONYMOUS_VAR: &str = "IPC_RS_TEST_SEMAPHORE";
+
+/// The same hand-off as `handoff`, but without giving the semaphore a name.
+#[cfg(unix)]
+fn anonymous() {
+    let sem = ipc::Semaphore::anonymous(0).unwrap();
+    let mut cmd = me();
+    cmd.arg("test4_inner");
+    sem.pass_to(&mut cmd, ANONYMOUS_VAR).unwrap();
+    assert!(cmd.status().unwrap().success());
+    println!("[0] Join");
+    assert!(sem.try_acquire().unwrap());
+    println!("[0] Acquire anonymous");
+    sem.remove().unwrap();
+}
+
+#[cfg(unix)]
+const WORKERS: usize = 4;
+
+/// A coordinator waiting on a latch for every worker to check in, without
+/// waiting for the workers themselves.
+#[cfg(unix)]
+fn latch() {
+    let _ = ipc::Latch::unlink("latch");
+    let latch = ipc::Latch::new("latch", WORKERS).unwrap();
+    assert!(!latch.try_wait().unwrap());
+    let children = (0..WORKERS).map(|_| {
+        me().arg("test5_inner").stdout(Stdio::null()).spawn().unwrap()
+    }).collect::<Vec<_>>();
+    assert!(latch.wait_timeout(Duration::from_secs(10)).unwrap());
+    println!("[0] Latch open");
+    assert_eq!(latch.count().unwrap(), 0);
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    latch.remove().unwrap();
+}
+
+#[cfg(unix)]
+const INCREMENTS: u64 = 1000;
+
+/// Several processes incrementing a counter in shared memory without losing
+/// any updates.
+#[cfg(unix)]
+fn counter() {
+    let _ = ipc::Mutex::<u64>::unlink("counter");
+    let counter = ipc::Mutex::new("counter", 0u64).unwrap();
+    let children = (0..WORKERS).map(|_| {
+        me().arg("test6_inner").stdout(Stdio::null()).spawn().unwrap()
+    }).collect::<Vec<_>>();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    println!("[0] Join counters");
+    assert_eq!(*counter.lock().unwrap(), WORKERS as u64 * INCREMENTS);
+    counter.remove().unwrap();
+}
+
+/// A process dying halfway through updating a value, and the next owner of
+/// the mutex being told about it.
+#[cfg(unix)]
+fn robust() {
+    let _ = ipc::Mutex::<[u32; 2]>::unlink("robust");
+    let m = ipc::Mutex::new("robust", [0u32; 2]).unwrap();
+    assert!(me().arg("test7_inner").status().unwrap().success());
+    println!("[0] Join");
+    match m.lock() {
+        Err(ipc::LockError::OwnerDied(mut g)) => {
+            println!("[0] Owner died");
+            assert_eq!(*g, [1, 0]);
+            g[1] = 1;
+        }
+        res => panic!("unexpected lock result: {:?}", res.map(|g| *g)),
+    }
+    assert_eq!(*m.lock().unwrap(), [1, 1]);
+    m.remove().unwrap();
+}
+
+/// Waiting for a child process to change a value, without polling.
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86", target_arch = "x86_64",
+              target_arch = "arm", target_arch = "aarch64")))]
+fn condvar() {
+    let _ = ipc::Mutex::<u32>::unlink("condvar");
+    let _ = ipc::Condvar::unlink("condvar");
+    let m = ipc::Mutex::new("condvar", 0u32).unwrap();
+    let c = ipc::Condvar::new("condvar").unwrap();
+    let mut g = m.lock().unwrap();
+    let mut child = me().arg("test8_inner").spawn().unwrap();
+    while *g == 0 {
+        g = c.wait(g).unwrap();
+    }
+    drop(g);
+    assert!(child.wait().unwrap().success());
+    println!("[0] Woken");
+    c.remove().unwrap();
+    m.remove().unwrap();
+}
+
+#[cfg(unix)]
+const GENERATIONS: usize = 10;
+
+/// Wait at `barrier` for every generation, returning how many times this
+/// process was the leader.
+#[cfg(unix)]
+fn meet(barrier: &ipc::Barrier) -> usize {
+    (0..GENERATIONS).filter(|_| barrier.wait().unwrap().is_leader()).count()
+}
+
+/// Processes meeting at a barrier repeatedly, with one leader each time.
+#[cfg(unix)]
+fn barrier() {
+    let _ = ipc::Barrier::unlink("barrier");
+    let barrier = ipc::Barrier::new("barrier", WORKERS + 1).unwrap();
+    let children = (0..WORKERS).map(|_| {
+        me().arg("test9_inner").stdout(Stdio::null()).spawn().unwrap()
+    }).collect::<Vec<_>>();
+    let mut leaders = meet(&barrier);
+    println!("[0] Passed barrier");
+    for mut child in children {
+        leaders += child.wait().unwrap().code().unwrap() as usize;
+    }
+    assert_eq!(leaders, GENERATIONS);
+    barrier.remove().unwrap();
+}
+
+fn run(test: &str, expected: &str) {
+    let output = me().arg(test).output().unwrap();
     assert! (output.status.success());
-    assert_eq! (str::from_utf8(&output.stdout).unwrap(),
-r#"Enter: test1
+    assert_eq! (str::from_utf8(&output.stdout).unwrap(), expected);
+}
+
+fn run_test() {
+    run("test1", r#"Enter: test1
 [0] Lock foo1
 [0] Start
 Enter: test1_inner
@@ -67,4 +302,58 @@ Leave: test1_inner
 [0] Join
 Leave: test1
 "#);
+    run("test2", r#"Enter: test2
+Enter: test2_inner
+[1] Release handoff
+Leave: test2_inner
+[0] Join
+[0] Acquire handoff
+Leave: test2
+"#);
+    run("test3", r#"Enter: test3
+[0] Join racers
+Leave: test3
+"#);
+    if cfg!(unix) {
+        run("test4", r#"Enter: test4
+Enter: test4_inner
+[1] Release anonymous
+Leave: test4_inner
+[0] Join
+[0] Acquire anonymous
+Leave: test4
+"#);
+        run("test5", r#"Enter: test5
+[0] Latch open
+Leave: test5
+"#);
+        run("test6", r#"Enter: test6
+[0] Join counters
+Leave: test6
+"#);
+        run("test7", r#"Enter: test7
+Enter: test7_inner
+[1] Die holding robust
+[0] Join
+[0] Owner died
+Leave: test7
+"#);
+    }
+    if cfg!(all(target_os = "linux",
+                any(target_arch = "x86", target_arch = "x86_64",
+                    target_arch = "arm", target_arch = "aarch64"))) {
+        run("test8", r#"Enter: test8
+Enter: test8_inner
+[1] Notify condvar
+Leave: test8_inner
+[0] Woken
+Leave: test8
+"#);
+    }
+    if cfg!(unix) {
+        run("test9", r#"Enter: test9
+[0] Passed barrier
+Leave: test9
+"#);
+    }
 }